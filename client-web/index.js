@@ -118,10 +118,33 @@ var sndNoise = (function() {
  * Input
  */
 var input = { x: 0.0, y: 0.0, r: 0.0, fire: false, mouse: [100, 100] };
+// Camera movement, when we don't have a ship
+var pan = { x: 0.0, y: 0.0 };
 function kbInput(evt, down) {
   if(down && evt.repeat) {
     return;
   }
+  // Enter opens the chat, the game doesn't see what is typed in there
+  if(evt.target === chatInput) {
+    return;
+  }
+  if(evt.code === 'Enter' && down && _wasm_instance) {
+    chatInput.style.display = 'block';
+    chatInput.focus();
+    evt.preventDefault();
+    return;
+  }
+  if(evt.code === 'ArrowLeft') {
+    pan.x = down ? -1.0 : 0.0;
+  } else if(evt.code === 'ArrowRight') {
+    pan.x = down ? 1.0 : 0.0;
+  } else if(evt.code === 'ArrowUp') {
+    pan.y = down ? 1.0 : 0.0;
+  } else if(evt.code === 'ArrowDown') {
+    pan.y = down ? -1.0 : 0.0;
+  } else if(evt.code === 'KeyF' && down && _wasm_instance) {
+    client_web.follow_next();
+  }
   if(evt.code === 'KeyS') {
     input.x = down ? -1.0 : 0.0;
   } else if(evt.code === 'KeyW') {
@@ -138,6 +161,19 @@ function kbInput(evt, down) {
     input.fire = down;
   }
 }
+var chatInput = document.getElementById('chat-input');
+chatInput.addEventListener('keydown', function(evt) {
+  if(evt.code === 'Enter') {
+    if(chatInput.value.trim() !== '') {
+      client_web.send_chat(chatInput.value);
+    }
+  } else if(evt.code !== 'Escape') {
+    return;
+  }
+  chatInput.value = '';
+  chatInput.style.display = 'none';
+  chatInput.blur();
+});
 document.addEventListener('keydown', function(e) { kbInput(e, true); });
 document.addEventListener('keyup', function(e) { kbInput(e, false); });
 document.addEventListener('mousemove', function(evt) {
@@ -195,6 +231,7 @@ function render(now) {
     delta, gl.drawingBufferWidth, gl.drawingBufferHeight,
     input.x, input.y, input.r, input.fire,
     input.mouse[0], input.mouse[1],
+    pan.x, pan.y,
   );
 
   // Reset alpha
@@ -273,11 +310,66 @@ function draw(position_x, position_y, angle, scale, color, buffer_id) {
   gl.drawArrays(gl.TRIANGLES, 0, buffer.length);
 }
 
+// Show network statistics from WebAssembly
+var hud = document.getElementById('hud');
+function set_hud(text) {
+  if(hud.innerText !== text) {
+    hud.innerText = text;
+  }
+}
+
+// Show chat messages from WebAssembly
+var chat = document.getElementById('chat');
+function set_chat(text) {
+  if(chat.innerText !== text) {
+    chat.innerText = text;
+  }
+}
+
+/*
+ * Network
+ */
+
+// Server to join, e.g. ?server=ws://localhost:34245, otherwise play alone.
+// Add &spectate to only watch, &name=... to pick the name shown to others
+var params = new URLSearchParams(window.location.search);
+var server = params.get('server');
+var spectate = params.has('spectate');
+var playerName = params.get('name') || '';
+var socket = undefined;
+
+function connect() {
+  socket = new WebSocket(server);
+  socket.binaryType = 'arraybuffer';
+  socket.onopen = function() {
+    client_web.connect(spectate, playerName);
+  };
+  socket.onmessage = function(evt) {
+    if(evt.data instanceof ArrayBuffer) {
+      client_web.receive(new Uint8Array(evt.data));
+    }
+  };
+  socket.onclose = function(evt) {
+    console.warn("Connection to server closed: ", evt.code, " ", evt.reason);
+  };
+}
+
+// Send from WebAssembly
+function ws_send(data) {
+  if(socket !== undefined && socket.readyState === WebSocket.OPEN) {
+    socket.send(data);
+  }
+}
+
 // Load module
 var _wasm_instance;
 client_web('client_web_bg.wasm')
 .then(function(obj) {
   _wasm_instance = obj;
 
+  if(server) {
+    connect();
+  }
+
   requestAnimationFrame(render);
 }, console.error);
@@ -1,8 +1,10 @@
 mod logger;
+mod net;
 mod primitives;
 mod render;
 
 use game::Game;
+use game::net::{Chat, ClientConfig};
 use game::input::{Input, Press};
 use log::{error, info, warn};
 use specs::WorldExt;
@@ -44,6 +46,57 @@ pub extern "C" fn start() {
     render::init();
 }
 
+/// Called by JavaScript once the WebSocket is open, to play on the server
+/// instead of alone, or only watch if `spectate` is set. The server picks a
+/// name if `name` is empty.
+#[wasm_bindgen]
+pub extern "C" fn connect(spectate: bool, name: &str) {
+    let mut app = match get_app() {
+        None => {
+            error!("connect() called before init()");
+            return;
+        }
+        Some(a) => a,
+    };
+    info!("Connected to server");
+    let config = ClientConfig {
+        spectate,
+        name: name.to_owned(),
+        ..Default::default()
+    };
+    app.game = Game::new_client(net::WebSocketClient, config);
+}
+
+/// Called by JavaScript when the player sends a chat message.
+#[wasm_bindgen]
+pub extern "C" fn send_chat(text: &str) {
+    let app = match get_app() {
+        None => {
+            error!("send_chat() called before init()");
+            return;
+        }
+        Some(a) => a,
+    };
+    match app.game.world.try_fetch_mut::<Chat>() {
+        Some(mut chat) => chat.send(text),
+        None => warn!("Can't chat, not connected to a server"),
+    };
+}
+
+/// Called by JavaScript to have the camera follow the next ship.
+#[wasm_bindgen]
+pub extern "C" fn follow_next() {
+    let mut app = match get_app() {
+        None => {
+            error!("follow_next() called before init()");
+            return;
+        }
+        Some(a) => a,
+    };
+    let App { game, render_app } = &mut *app;
+    render_app.follow_next(&game.world);
+}
+
 #[wasm_bindgen]
 pub extern "C" fn update(
     // Simulation delta
@@ -53,6 +106,8 @@ pub extern "C" fn update(
     // Input
     x: f32, y: f32, r: f32, fire: bool,
     mouse_x: f32, mouse_y: f32,
+    // Camera movement, when we don't have a ship
+    pan_x: f32, pan_y: f32,
 ) {
     let mut app = match get_app() {
         None => {
@@ -74,6 +129,7 @@ pub extern "C" fn update(
         input.fire = if fire { Press::PRESSED } else { Press::UP };
         input.mouse = app.render_app.project_cursor([mouse_x, mouse_y]);
     }
+    app.render_app.pan([pan_x, pan_y], delta);
 
     while delta > 0.0 {
         if delta > MAX_TIME_STEP {
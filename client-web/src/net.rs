@@ -0,0 +1,45 @@
+//! WebSocket transport, to play online from the browser.
+//!
+//! The WebSocket itself lives on the JavaScript side, which hands us the
+//! messages it receives through `receive()`.
+
+use game::net::Client;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+extern "C" {
+    fn ws_send(data: &[u8]);
+}
+
+thread_local! {
+    static RECEIVED: RefCell<VecDeque<Vec<u8>>> = Default::default();
+}
+
+/// Called by JavaScript when a message arrives on the WebSocket.
+#[wasm_bindgen]
+pub fn receive(data: &[u8]) {
+    RECEIVED.with(|r| r.borrow_mut().push_back(data.to_vec()));
+}
+
+pub struct WebSocketClient;
+
+impl Client for WebSocketClient {
+    fn send(&self, msg: &[u8]) -> io::Result<usize> {
+        ws_send(msg);
+        Ok(msg.len())
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match RECEIVED.with(|r| r.borrow_mut().pop_front()) {
+            Some(data) => {
+                let len = data.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&data[..len]);
+                Ok(len)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
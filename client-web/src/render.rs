@@ -1,9 +1,12 @@
 use game::blocks::{BlockInner, Blocky};
 use game::guns::{Projectile, ProjectileType};
+use game::net::{Chat, NetStats};
 use game::particles::{Particle, ParticleType};
 use game::physics::{LocalControl, Position};
+use game::ship::Ship;
+use game::WorldConfig;
 use log::info;
-use specs::{Entity, Join};
+use specs::{Entity, Join, World};
 use specs::world::WorldExt;
 use std::collections::hash_map::{Entry, HashMap};
 use std::collections::HashSet;
@@ -25,11 +28,19 @@ extern "C" {
         color: &[f32],
         buffer_id: f64,
     );
+    fn set_hud(text: &str);
+    fn set_chat(text: &str);
 }
 
 const MAX_RATIO: f32 = 1.6;
 const VIEWPORT_SIZE: f32 = 80.0;
 
+/// Speed of the free camera, in units per second
+const CAMERA_SPEED: f32 = 60.0;
+
+/// Number of chat messages shown
+const CHAT_LINES: usize = 8;
+
 // IDs of common buffers created in init()
 const EXTRA_BUFS_BASE: f64 = (1u64 << 40) as f64;
 
@@ -51,13 +62,31 @@ fn entity_buffer(entity_id: u32, buffer_id: u32) -> f64 {
 // "Default color", white (no modulation)
 const DEF_COLOR: &[f32] = &[1.0, 1.0, 1.0, 1.0];
 
+/// Where the camera goes when we don't have a ship, e.g. spectating
+#[derive(Clone, Copy, PartialEq)]
+enum CameraMode {
+    /// Moved around with the arrow keys
+    Free,
+    /// Stays on someone's ship
+    Follow(Entity),
+}
+
+impl Default for CameraMode {
+    fn default() -> CameraMode {
+        CameraMode::Free
+    }
+}
+
 /// Global information kept by the render module
 #[derive(Default)]
 pub struct RenderApp {
     viewport: [u32; 2],
     scale: [f32; 2],
     camera: [f32; 2],
+    camera_mode: CameraMode,
     blocky_buffers: HashMap<u32, (Entity, Wrapping<u32>)>,
+    /// Size of the world the bounds buffer was made for.
+    bounds_size: f32,
 }
 
 impl RenderApp {
@@ -87,6 +116,38 @@ impl RenderApp {
         true
     }
 
+    /// Move the free camera, leaving the ship we were following if any
+    pub fn pan(&mut self, direction: [f32; 2], dt: f32) {
+        if direction == [0.0, 0.0] {
+            return;
+        }
+        self.camera_mode = CameraMode::Free;
+        self.camera = vec2_add(
+            self.camera,
+            vec2_scale(direction, CAMERA_SPEED * dt),
+        );
+    }
+
+    /// Follow the next ship, in order of entity ID
+    pub fn follow_next(&mut self, world: &World) {
+        let entities = world.entities();
+        let ships = world.read_component::<Ship>();
+        let current = match self.camera_mode {
+            CameraMode::Follow(ent) => Some(ent.id()),
+            CameraMode::Free => None,
+        };
+        let ships: Vec<Entity> =
+            (&*entities, &ships).join().map(|(ent, _)| ent).collect();
+        let next = ships
+            .iter()
+            .find(|ent| current.map_or(true, |id| ent.id() > id))
+            .or_else(|| ships.first());
+        self.camera_mode = match next {
+            Some(&ent) => CameraMode::Follow(ent),
+            None => CameraMode::Free,
+        };
+    }
+
     /// Project back the cursor position from screen coordinates to game
     pub fn project_cursor(&self, screen_coords: [f32; 2]) -> [f32; 2] {
         [
@@ -96,17 +157,20 @@ impl RenderApp {
     }
 }
 
-/// Initialize the rendering module (create the common buffers)
-pub fn init() {
+/// Create the buffer for the bounds of a world of the given size
+fn store_bounds(size: f32) {
     let mut bounds = VertexVecs::default();
     bounds.hollow_rect(
-        [-105.0, -105.0],
-        [105.0, 105.0],
+        [-size - 5.0, -size - 5.0],
+        [size + 5.0, size + 5.0],
         10.0,
         [0.8, 0.8, 0.8, 1.0],
     );
     bounds.store(BUF_BOUNDS, BufType::STATIC);
+}
 
+/// Initialize the rendering module (create the common buffers)
+pub fn init() {
     let mut plasma = VertexVecs::default();
     plasma.line(
         [-0.8, 0.0], [0.8, 0.0],
@@ -163,10 +227,20 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
     let projectile = world.read_component::<Projectile>();
     let particle = world.read_component::<Particle>();
 
-    // Update camera location
+    // Update camera location: on our ship, or where the player put it
     app.render_app.set_viewport(viewport);
+    let mut own_ship = false;
     for (pos, _) in (&pos, &local).join() {
         app.render_app.camera = pos.pos;
+        own_ship = true;
+    }
+    if let (false, CameraMode::Follow(ent)) =
+        (own_ship, app.render_app.camera_mode)
+    {
+        match pos.get(ent) {
+            Some(pos) => app.render_app.camera = pos.pos,
+            None => app.render_app.camera_mode = CameraMode::Free,
+        }
     }
     set_camera(
         app.render_app.camera[0], app.render_app.camera[1],
@@ -180,6 +254,11 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
     // TODO: Background
 
     // Bounds
+    let size = world.read_resource::<WorldConfig>().size;
+    if size != app.render_app.bounds_size {
+        store_bounds(size);
+        app.render_app.bounds_size = size;
+    }
     draw(0.0, 0.0, 0.0, 1.0, DEF_COLOR, BUF_BOUNDS);
 
     // Draw blocks
@@ -319,6 +398,44 @@ pub fn render(app: &mut App, viewport: [u32; 2]) {
             }
         }
     }
+
+    // Network statistics, when playing online, and how to move the camera
+    // without a ship
+    if let Some(stats) = world.try_fetch::<NetStats>() {
+        let mut hud = format!(
+            "ping {:.0} ms, jitter {:.0} ms, loss {:.0}%, in {:.1} kB/s, \
+             out {:.1} kB/s",
+            stats.rtt * 1000.0,
+            stats.jitter * 1000.0,
+            stats.packet_loss * 100.0,
+            stats.bytes_in / 1000.0,
+            stats.bytes_out / 1000.0,
+        );
+        if !own_ship {
+            hud.push_str(match app.render_app.camera_mode {
+                CameraMode::Free => {
+                    "\nFree camera: arrow keys to move, F to follow a ship"
+                }
+                CameraMode::Follow(_) => {
+                    "\nFollowing a ship: F for the next one, arrow keys to \
+                     move freely"
+                }
+            });
+        }
+        set_hud(&hud);
+    }
+
+    // Latest chat messages, when playing online
+    if let Some(chat) = world.try_fetch::<Chat>() {
+        let skip = chat.log.len().saturating_sub(CHAT_LINES);
+        let lines: Vec<String> = chat
+            .log
+            .iter()
+            .skip(skip)
+            .map(|line| format!("{}: {}", line.sender, line.text))
+            .collect();
+        set_chat(&lines.join("\n"));
+    }
 }
 
 /// Generate vertex buffers for a Blocky object
@@ -0,0 +1,112 @@
+//! Controllers driving a client's ship in place of a player.
+
+use game::blocks::Blocky;
+use game::input::{Input, Press};
+use game::physics::{LocalControl, Position};
+use game::utils::angle_wrap;
+use game::Game;
+use specs::{Join, WorldExt};
+use std::str::FromStr;
+use vecmath::{vec2_len, vec2_sub};
+
+/// Distance under which the hunter stops closing in.
+const HUNT_DISTANCE: f32 = 20.0;
+
+/// Distance under which the hunter opens fire.
+const FIRE_DISTANCE: f32 = 40.0;
+
+/// How a bot flies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotKind {
+    /// Doesn't touch the controls.
+    Idle,
+    /// Flies in circles, firing in bursts.
+    Circle,
+    /// Goes after the closest ship or asteroid and shoots at it.
+    Hunter,
+}
+
+impl FromStr for BotKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BotKind, String> {
+        match s {
+            "idle" => Ok(BotKind::Idle),
+            "circle" => Ok(BotKind::Circle),
+            "hunter" => Ok(BotKind::Hunter),
+            _ => Err(format!("Unknown bot {:?}", s)),
+        }
+    }
+}
+
+/// Drives the locally-controlled ship of a game, through its `Input`.
+pub struct Bot {
+    kind: BotKind,
+    time: f32,
+}
+
+impl Bot {
+    pub fn new(kind: BotKind) -> Bot {
+        Bot { kind, time: 0.0 }
+    }
+
+    /// Sets the controls for the next update.
+    pub fn control(&mut self, game: &Game, dt: f32) {
+        self.time += dt;
+        let mut input = Input::default();
+        match self.kind {
+            BotKind::Idle => return,
+            BotKind::Circle => {
+                input.movement = [1.0, 0.0];
+                // Turn for 2 seconds out of 3, fire half of the time
+                if self.time % 3.0 < 2.0 {
+                    input.rotation = 1.0;
+                }
+                if self.time % 2.0 < 1.0 {
+                    input.fire = Press::PRESSED;
+                }
+                input.mouse = [10.0, 0.0];
+            }
+            BotKind::Hunter => hunt(game, &mut input),
+        }
+        *game.world.write_resource::<Input>() = input;
+    }
+}
+
+/// Steers towards the closest thing and shoots at it.
+fn hunt(game: &Game, input: &mut Input) {
+    let entities = game.world.entities();
+    let position = game.world.read_component::<Position>();
+    let local = game.world.read_component::<LocalControl>();
+    let blocky = game.world.read_component::<Blocky>();
+
+    let me = match (&position, &local).join().next() {
+        Some((pos, _)) => pos,
+        None => return,
+    };
+    // Physics can blow up, don't go after things that are nowhere
+    let target = (&*entities, &position, &blocky, !&local)
+        .join()
+        .map(|(_, pos, _, _)| vec2_sub(pos.pos, me.pos))
+        .filter(|rel| vec2_len(*rel).is_finite())
+        .min_by(|a, b| vec2_len(*a).total_cmp(&vec2_len(*b)));
+    let target = match target {
+        Some(t) => t,
+        None => return,
+    };
+
+    let distance = vec2_len(target);
+    let angle = angle_wrap(target[1].atan2(target[0]) - me.rot);
+    if angle > 0.1 {
+        input.rotation = 1.0;
+    } else if angle < -0.1 {
+        input.rotation = -1.0;
+    }
+    if distance > HUNT_DISTANCE && angle.abs() < 0.5 {
+        input.movement = [1.0, 0.0];
+    }
+    input.mouse = target;
+    if distance < FIRE_DISTANCE {
+        input.fire = Press::PRESSED;
+    }
+}
@@ -0,0 +1,297 @@
+//! Headless client, for bots and load testing.
+//!
+//! Connects one or more clients to a server, each with its ship driven by a
+//! bot, and logs the bandwidth they use and how long their ticks take.
+
+mod bot;
+
+use game::Game;
+use game::net::{ClientConfig, NetStats};
+use game::net::discovery::{discover, DEFAULT_PORT};
+use game::net::udp::UdpClient;
+use game::physics::LocalControl;
+use log::{info, warn};
+use specs::{Join, WorldExt};
+use std::env;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::bot::{Bot, BotKind};
+
+const TIME_STEP: f32 = 0.040;
+
+/// Interval at which statistics are logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for servers to answer `--list`.
+const DISCOVERY_WAIT: Duration = Duration::from_secs(1);
+
+const USAGE: &str = "\
+Usage: client [options] [server[:port]]
+
+Connects to a server (default localhost) with bots.
+
+Options:
+  --clients N      number of clients to connect (default 1)
+  --bot KIND       how ships are driven: idle, circle or hunter (default
+                   hunter)
+  --duration SECS  disconnect and exit after that many seconds
+  --room ID        game room to join on the server (default 0)
+  --spectate       join without ships, only receiving updates
+  --name NAME      name shown to other players, numbered if there are
+                   several clients (default picked by the server)
+  --list           list the servers on the local network, and exit
+  --help           show this message";
+
+struct Options {
+    server: String,
+    clients: usize,
+    bot: BotKind,
+    duration: Option<Duration>,
+    room: u32,
+    spectate: bool,
+    name: Option<String>,
+    list: bool,
+}
+
+fn parse_args<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<Options, String> {
+    let mut options = Options {
+        server: "localhost".to_owned(),
+        clients: 1,
+        bot: BotKind::Hunter,
+        duration: None,
+        room: 0,
+        spectate: false,
+        name: None,
+        list: false,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("Missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--clients" => {
+                options.clients = value("--clients")?
+                    .parse()
+                    .map_err(|_| "Invalid number of clients".to_owned())?;
+            }
+            "--bot" => options.bot = value("--bot")?.parse()?,
+            "--duration" => {
+                let secs: f32 = value("--duration")?
+                    .parse()
+                    .map_err(|_| "Invalid duration".to_owned())?;
+                if secs.is_nan() || secs <= 0.0 {
+                    return Err("Invalid duration".to_owned());
+                }
+                options.duration = Some(Duration::from_secs_f32(secs));
+            }
+            "--room" => {
+                options.room = value("--room")?
+                    .parse()
+                    .map_err(|_| "Invalid room".to_owned())?;
+            }
+            "--spectate" => options.spectate = true,
+            "--name" => options.name = Some(value("--name")?),
+            "--list" => options.list = true,
+            "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            a if a.starts_with('-') => {
+                return Err(format!("Unknown option {}", a));
+            }
+            a => options.server = a.to_owned(),
+        }
+    }
+    if options.clients == 0 {
+        return Err("Need at least one client".to_owned());
+    }
+    Ok(options)
+}
+
+/// Finds the address of the server, using the default port if none is
+/// given.
+fn resolve(server: &str) -> Result<SocketAddr, String> {
+    let server = if server.contains(':') {
+        server.to_owned()
+    } else {
+        format!("{}:{}", server, DEFAULT_PORT)
+    };
+    server
+        .to_socket_addrs()
+        .map_err(|e| format!("Can't resolve {}: {}", server, e))?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| format!("No IPv4 address for {}", server))
+}
+
+/// Prints the servers found on the local network.
+fn list_servers() {
+    let servers = match discover(DEFAULT_PORT, DISCOVERY_WAIT) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Can't look for servers: {}", e);
+            process::exit(1);
+        }
+    };
+    if servers.is_empty() {
+        println!("No server found");
+    }
+    for server in servers {
+        let info = &server.info;
+        println!(
+            "{}  {}  {}/{} players  map {}{}",
+            server.address,
+            info.name,
+            info.players,
+            info.max_players,
+            info.map,
+            if info.compatible() { "" } else { "  (incompatible)" },
+        );
+    }
+}
+
+/// Time taken by client ticks, since the last report.
+struct TickTimes {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl TickTimes {
+    fn new() -> TickTimes {
+        TickTimes {
+            count: 0,
+            total: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+        }
+    }
+
+    fn record(&mut self, time: Duration) {
+        self.count += 1;
+        self.total += time;
+        self.max = self.max.max(time);
+    }
+}
+
+/// Logs the bandwidth and tick times.
+fn report(clients: &[(Game, Bot)], times: &TickTimes) {
+    let stats: Vec<NetStats> = clients
+        .iter()
+        .map(|(game, _)| game.world.read_resource::<NetStats>())
+        .map(|stats| (*stats).clone())
+        .collect();
+    let total = NetStats::combine(&stats);
+    let playing = clients
+        .iter()
+        .filter(|(game, _)| {
+            let local = game.world.read_component::<LocalControl>();
+            (&local).join().next().is_some()
+        })
+        .count();
+    info!(
+        "{}/{} clients playing: ping {:.0} ms, loss {:.0}%, \
+         received {:.0} B/s, sent {:.0} B/s",
+        playing,
+        clients.len(),
+        total.rtt * 1000.0,
+        total.packet_loss * 100.0,
+        total.bytes_in,
+        total.bytes_out,
+    );
+    if times.count > 0 {
+        info!(
+            "Tick of all clients: {:.2} ms average, {:.2} ms max",
+            times.total.as_secs_f32() * 1000.0 / times.count as f32,
+            times.max.as_secs_f32() * 1000.0,
+        );
+    }
+}
+
+/// Entrypoint for headless client.
+fn main() {
+    color_logger::init(log::Level::Info).unwrap();
+
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.list {
+        list_servers();
+        return;
+    }
+    let address = match resolve(&options.server) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    info!(
+        "Connecting {} clients to room {} of {}, driven by {:?} bots",
+        options.clients, options.room, address, options.bot
+    );
+    let mut clients: Vec<(Game, Bot)> = (0..options.clients)
+        .map(|i| {
+            let name = match options.name {
+                Some(ref name) if options.clients > 1 => {
+                    format!("{} {}", name, i + 1)
+                }
+                Some(ref name) => name.clone(),
+                None => String::new(),
+            };
+            let config = ClientConfig {
+                room: options.room,
+                spectate: options.spectate,
+                name,
+                ..Default::default()
+            };
+            let game = Game::new_client(UdpClient::new(address), config);
+            (game, Bot::new(options.bot))
+        })
+        .collect();
+
+    let step = Duration::from_secs_f32(TIME_STEP);
+    let start = Instant::now();
+    let mut next = start;
+    let mut last_report = start;
+    let mut times = TickTimes::new();
+    loop {
+        let tick_start = Instant::now();
+        for (game, bot) in &mut clients {
+            bot.control(game, TIME_STEP);
+            game.update(TIME_STEP);
+        }
+        let now = Instant::now();
+        times.record(now - tick_start);
+
+        if now - last_report >= REPORT_INTERVAL {
+            report(&clients, &times);
+            times = TickTimes::new();
+            last_report = now;
+        }
+        if let Some(duration) = options.duration {
+            if now - start >= duration {
+                break;
+            }
+        }
+
+        next += step;
+        if next > now {
+            sleep(next - now);
+        } else if now - next > Duration::from_millis(500) {
+            warn!("Clients can't keep up, skipping ahead");
+            next = now;
+        }
+    }
+
+    // Dropping the games tells the server we are leaving
+    info!("Disconnecting");
+}
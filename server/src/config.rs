@@ -0,0 +1,387 @@
+//! Server settings, from the command line and an optional config file.
+//!
+//! The config file has one `key = value` setting per line, using the same
+//! names as the command-line options, and `#` starts a comment. Options
+//! given on the command line override the file. Files named in the config
+//! file are found relative to it.
+
+use game::net::discovery::DEFAULT_PORT;
+use game::net::rooms::RoomsConfig;
+use game::net::{ChatFilter, ServerConfig, WordFilter};
+use game::WorldConfig;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+pub const USAGE: &str = "\
+Usage: server [options]
+
+Options:
+  --config FILE          read settings from FILE, as `key = value` lines
+  --address ADDR         address to listen on (default 0.0.0.0)
+  --port PORT            UDP port (default 34244)
+  --websocket-port PORT  WebSocket port (default UDP port + 1)
+  --tick-rate HZ         simulation updates per second (default 12.5)
+  --max-players N        most clients in each room (default 32)
+  --max-rooms N          most game rooms open at once (default 8)
+  --world-size SIZE      half the width of the play area (default 100)
+  --asteroids N          number of asteroids floating around (default 60)
+  --log-level LEVEL      error, warn, info, debug or trace (default info)
+  --name NAME            name shown to clients looking for servers
+  --max-chat-length N    longest chat message, in characters (default 200)
+  --chat-rate N          chat messages per second from each player, after a
+                         burst of 5 (default 0.5)
+  --chat-filter FILE     mask the words listed in FILE, one per line, in
+                         chat and player names
+  --help                 show this message";
+
+/// Server settings.
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Defaults to the UDP port + 1.
+    pub websocket_port: Option<u16>,
+    pub tick_rate: f32,
+    pub max_players: usize,
+    pub max_rooms: usize,
+    pub world_size: f32,
+    pub asteroids: usize,
+    pub log_level: log::Level,
+    pub name: String,
+    pub max_chat_length: usize,
+    pub chat_rate: f32,
+    /// Words masked in chat, read from the `chat-filter` file.
+    pub chat_filter: Option<Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let server = ServerConfig::default();
+        let world = WorldConfig::default();
+        let rooms = RoomsConfig::default();
+        Config {
+            address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            port: DEFAULT_PORT,
+            websocket_port: None,
+            tick_rate: 12.5,
+            max_players: server.max_clients,
+            max_rooms: rooms.max_rooms,
+            world_size: world.size,
+            asteroids: world.asteroids,
+            log_level: log::Level::Info,
+            name: server.name,
+            max_chat_length: server.max_chat_length,
+            chat_rate: server.chat_rate,
+            chat_filter: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings from the command-line arguments, and the config
+    /// file they point to if any.
+    pub fn from_args<I: Iterator<Item = String>>(
+        mut args: I,
+    ) -> Result<Config, String> {
+        let mut options = Vec::new();
+        let mut file = None;
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            let key = match arg.strip_prefix("--") {
+                Some(k) => k.to_owned(),
+                None => return Err(format!("Unexpected argument {}", arg)),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+            if key == "config" {
+                file = Some(value);
+            } else {
+                options.push((key, value));
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(file) = file {
+            config.read_file(&file)?;
+        }
+        for (key, value) in options {
+            config.set(&key, &value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Applies the settings from a config file.
+    fn read_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path, e))?;
+        for (num, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut split = line.splitn(2, '=');
+            let key = split.next().unwrap().trim();
+            let value = match split.next() {
+                Some(v) => v.trim(),
+                None => {
+                    return Err(format!(
+                        "{}:{}: Expected `key = value`",
+                        path,
+                        num + 1
+                    ))
+                }
+            };
+            let value = if key == "chat-filter" {
+                let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+                dir.join(value).to_string_lossy().into_owned()
+            } else {
+                value.to_owned()
+            };
+            self.set(key, &value)
+                .map_err(|e| format!("{}:{}: {}", path, num + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Changes one setting.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(
+            key: &str,
+            value: &str,
+        ) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {:?}", key, value))
+        }
+
+        match key {
+            "address" => self.address = parse(key, value)?,
+            "port" => self.port = parse(key, value)?,
+            "websocket-port" => self.websocket_port = Some(parse(key, value)?),
+            "tick-rate" => self.tick_rate = parse(key, value)?,
+            "max-players" => self.max_players = parse(key, value)?,
+            "max-rooms" => self.max_rooms = parse(key, value)?,
+            "world-size" => self.world_size = parse(key, value)?,
+            "asteroids" => self.asteroids = parse(key, value)?,
+            "log-level" => self.log_level = parse(key, value)?,
+            "name" => self.name = value.to_owned(),
+            "max-chat-length" => self.max_chat_length = parse(key, value)?,
+            "chat-rate" => self.chat_rate = parse(key, value)?,
+            "chat-filter" => {
+                let words = fs::read_to_string(value)
+                    .map_err(|e| format!("Can't read {}: {}", value, e))?;
+                let words = words.lines().map(|w| w.trim().to_owned());
+                self.chat_filter = Some(words.collect());
+            }
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Checks that the settings make sense together.
+    fn validate(&self) -> Result<(), String> {
+        if self.port == 0 {
+            return Err("Port can't be 0".to_owned());
+        }
+        if self.websocket_port == Some(0) {
+            return Err("WebSocket port can't be 0".to_owned());
+        }
+        if self.websocket_port.is_none() && self.port == u16::MAX {
+            return Err(format!(
+                "No port after {} for WebSocket, set --websocket-port",
+                self.port
+            ));
+        }
+        if !(self.tick_rate >= 1.0 && self.tick_rate <= 100.0) {
+            return Err("Tick rate should be between 1 and 100".to_owned());
+        }
+        if self.max_players == 0 || self.max_players > u16::MAX as usize {
+            return Err(format!(
+                "Max players should be between 1 and {}",
+                u16::MAX
+            ));
+        }
+        if self.max_rooms == 0 || self.max_rooms > 1000 {
+            return Err("Max rooms should be between 1 and 1000".to_owned());
+        }
+        if !(self.world_size >= 20.0 && self.world_size <= 10_000.0) {
+            return Err("World size should be between 20 and 10000".to_owned());
+        }
+        if self.asteroids > 1000 {
+            return Err("Can't have more than 1000 asteroids".to_owned());
+        }
+        if self.name.is_empty() {
+            return Err("Server name can't be empty".to_owned());
+        }
+        if self.max_chat_length == 0 || self.max_chat_length > 500 {
+            return Err(
+                "Max chat length should be between 1 and 500".to_owned()
+            );
+        }
+        if !(self.chat_rate > 0.0 && self.chat_rate <= 100.0) {
+            return Err("Chat rate should be above 0, up to 100".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn websocket_port(&self) -> u16 {
+        self.websocket_port.unwrap_or(self.port + 1)
+    }
+
+    /// Duration of a simulation step, in seconds.
+    pub fn time_step(&self) -> f32 {
+        1.0 / self.tick_rate
+    }
+
+    pub fn rooms_config(&self) -> RoomsConfig {
+        RoomsConfig {
+            server: ServerConfig {
+                name: self.name.clone(),
+                max_clients: self.max_players,
+                max_chat_length: self.max_chat_length,
+                chat_rate: self.chat_rate,
+                chat_filter: self.chat_filter.as_ref().map(|words| {
+                    Arc::new(WordFilter::new(words)) as Arc<dyn ChatFilter>
+                }),
+                ..Default::default()
+            },
+            world: WorldConfig {
+                size: self.world_size,
+                asteroids: self.asteroids,
+            },
+            max_rooms: self.max_rooms,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::Config;
+
+    fn from_args(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|&a| a.to_owned()))
+    }
+
+    /// Creates an empty directory for a test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("server-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_args() {
+        let config = from_args(&[]).unwrap();
+        assert_eq!(config.port, 34244);
+        assert_eq!(config.websocket_port(), 34245);
+        assert!(config.chat_filter.is_none());
+
+        let config = from_args(&[
+            "--port",
+            "4000",
+            "--name",
+            "Test server",
+            "--tick-rate",
+            "20",
+        ])
+        .unwrap();
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.websocket_port(), 4001);
+        assert_eq!(config.name, "Test server");
+        assert_eq!(config.time_step(), 0.05);
+    }
+
+    #[test]
+    fn test_bad_args() {
+        let errors = [
+            (&["port"][..], "Unexpected argument port"),
+            (&["--port"][..], "Missing value for --port"),
+            (&["--port", "abc"][..], "Invalid value for port: \"abc\""),
+            (&["--speed", "2"][..], "Unknown setting speed"),
+            (&["--port", "0"][..], "Port can't be 0"),
+            (&["--tick-rate", "0.5"][..], "Tick rate should be between"),
+            (&["--name", ""][..], "Server name can't be empty"),
+            (&["--chat-filter", "/nonexistent/words"][..], "Can't read"),
+        ];
+        for &(args, error) in &errors {
+            match from_args(args) {
+                Ok(_) => panic!("{:?} should be rejected", args),
+                Err(e) => assert!(e.starts_with(error), "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = test_dir("file");
+        fs::write(dir.join("words.txt"), "darn\nheck\n").unwrap();
+        let file = dir.join("server.conf");
+        fs::write(
+            &file,
+            "# Test settings\n\
+             \n\
+             port = 5000\n\
+             tick-rate = 20  # faster\n\
+             name = My server\n\
+             chat-filter = words.txt\n",
+        )
+        .unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = from_args(&["--config", file]).unwrap();
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.tick_rate, 20.0);
+        assert_eq!(config.name, "My server");
+        let words = vec!["darn".to_owned(), "heck".to_owned()];
+        assert_eq!(config.chat_filter, Some(words));
+
+        // The command line has the last word
+        let config =
+            from_args(&["--port", "6000", "--config", file]).unwrap();
+        assert_eq!(config.port, 6000);
+        assert_eq!(config.tick_rate, 20.0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_file() {
+        let dir = test_dir("bad-file");
+        let file = dir.join("server.conf");
+        let path = file.to_str().unwrap();
+
+        let mut config = Config::default();
+        assert!(config.read_file(path).unwrap_err().starts_with("Can't read"));
+
+        fs::write(&file, "port = 5000\nasteroids\n").unwrap();
+        let error = config.read_file(path).unwrap_err();
+        assert_eq!(error, format!("{}:2: Expected `key = value`", path));
+
+        fs::write(&file, "world-size = 5\n").unwrap();
+        let mut config = Config::default();
+        config.read_file(path).unwrap();
+        assert!(config.validate().is_err());
+        config.set("world-size", "50").unwrap();
+        assert!(config.validate().is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+//! Command console, letting the operator act on the running server.
+//!
+//! Commands are read from stdin one line at a time, on a separate thread so
+//! the game loop never waits for them.
+
+use game::asteroid::Asteroid;
+use game::net::rooms::Rooms;
+use game::net::{Admin, Chat, Server};
+use game::ship::Ship;
+use log::info;
+use specs::WorldExt;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+const HELP: &str = "\
+Commands:
+  rooms                   list open rooms
+  clients                 list connected clients
+  kick ID                 disconnect a client, deleting its ships
+  say TEXT                send a chat message to every room
+  asteroid X Y [ROOM]     spawn an asteroid (default room 0)
+  ship X Y [ROOM]         spawn a ship, controlled by no one
+  tickrate HZ             change the number of updates per second
+  profile                 log entity counts of each room, at info level
+  shutdown                disconnect all clients and stop the server
+  help                    show this message";
+
+/// A command from the operator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Rooms,
+    Clients,
+    Kick(u64),
+    Say(String),
+    SpawnAsteroid([f32; 2], u32),
+    SpawnShip([f32; 2], u32),
+    TickRate(f32),
+    Profile,
+    Shutdown,
+}
+
+impl Command {
+    /// Reads a command from a line of input, `None` if it is blank.
+    pub fn parse(line: &str) -> Result<Option<Command>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (&name, args) = match words.split_first() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        fn number<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+            arg.parse().map_err(|_| format!("Invalid number {:?}", arg))
+        }
+        let position = || {
+            let (x, y, room) = match *args {
+                [x, y] => (x, y, 0),
+                [x, y, room] => (x, y, number(room)?),
+                _ => return Err(format!("Usage: {} X Y [ROOM]", name)),
+            };
+            let pos = [number::<f32>(x)?, number::<f32>(y)?];
+            if pos[0].is_finite() && pos[1].is_finite() {
+                Ok((pos, room))
+            } else {
+                Err("Invalid position".to_owned())
+            }
+        };
+        let command = match (name, args) {
+            ("help", []) => Command::Help,
+            ("rooms", []) => Command::Rooms,
+            ("clients", []) => Command::Clients,
+            ("kick", [id]) => Command::Kick(number(id)?),
+            ("say", [_, ..]) => Command::Say(args.join(" ")),
+            ("asteroid", _) => {
+                let (pos, room) = position()?;
+                Command::SpawnAsteroid(pos, room)
+            }
+            ("ship", _) => {
+                let (pos, room) = position()?;
+                Command::SpawnShip(pos, room)
+            }
+            ("tickrate", [rate]) => {
+                let rate: f32 = number(rate)?;
+                if !(1.0..=100.0).contains(&rate) {
+                    return Err(
+                        "Tick rate should be between 1 and 100".to_owned()
+                    );
+                }
+                Command::TickRate(rate)
+            }
+            ("profile", []) => Command::Profile,
+            ("shutdown", []) => Command::Shutdown,
+            _ => {
+                return Err(format!(
+                    "Invalid command {:?}, try \"help\"",
+                    line.trim()
+                ))
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Reads commands from stdin.
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn start() -> Console {
+        let (sender, lines) = channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(l) => l,
+                    Err(_) => break,
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Console { lines }
+    }
+
+    /// Returns the next command that came in, if any. Invalid commands are
+    /// reported to the operator and skipped.
+    pub fn poll(&self) -> Option<Command> {
+        loop {
+            let line = match self.lines.try_recv() {
+                Ok(l) => l,
+                Err(TryRecvError::Empty) => return None,
+                // Stdin is closed, that's fine when running in the
+                // background
+                Err(TryRecvError::Disconnected) => return None,
+            };
+            match Command::parse(&line) {
+                Ok(Some(command)) => return Some(command),
+                Ok(None) => {}
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+/// Carries out a command. Returns false if the server should stop.
+pub fn execute<S: Server>(
+    command: Command,
+    rooms: &mut Rooms<S>,
+    time_step: &mut f32,
+) -> bool {
+    match command {
+        Command::Help => println!("{}", HELP),
+        Command::Rooms => {
+            if rooms.rooms().next().is_none() {
+                println!("No rooms open");
+            }
+            for (id, game) in rooms.rooms() {
+                let admin = game.world.read_resource::<Admin>();
+                println!(
+                    "{:>6}  {} clients, {} reconnecting",
+                    id,
+                    admin.clients.len(),
+                    admin.reconnecting,
+                );
+            }
+        }
+        Command::Clients => {
+            let mut any = false;
+            for (id, game) in rooms.rooms() {
+                let admin = game.world.read_resource::<Admin>();
+                for client in &admin.clients {
+                    any = true;
+                    let ships = if client.spectator {
+                        "spectating".to_owned()
+                    } else {
+                        format!("{} ships", client.ships)
+                    };
+                    println!(
+                        "{:>12}  room {:<4}  {:<24}  {:<21}  ping {:>4.0} ms  \
+                         {}",
+                        client.client_id,
+                        id,
+                        client.name,
+                        client.address,
+                        client.rtt * 1000.0,
+                        ships,
+                    );
+                }
+            }
+            if !any {
+                println!("No clients connected");
+            }
+        }
+        // Client IDs start with their room's
+        Command::Kick(client_id) => match rooms.get((client_id >> 32) as u32) {
+            Some(game) => game.world.write_resource::<Admin>().kick(client_id),
+            None => println!("No client {}", client_id),
+        },
+        Command::Say(text) => {
+            if rooms.rooms().next().is_none() {
+                println!("No rooms open");
+            }
+            for (_, game) in rooms.rooms() {
+                game.world.write_resource::<Chat>().send(&text);
+            }
+        }
+        Command::SpawnAsteroid(pos, room) => match rooms.get(room) {
+            Some(game) => {
+                let world = &game.world;
+                Asteroid::create(
+                    &world.entities(),
+                    &world.system_data(),
+                    pos,
+                    [0.0, 0.0],
+                );
+            }
+            None => println!("No room {}", room),
+        },
+        Command::SpawnShip(pos, room) => match rooms.get(room) {
+            Some(game) => {
+                let world = &game.world;
+                Ship::create_at(&world.entities(), &world.system_data(), pos);
+            }
+            None => println!("No room {}", room),
+        },
+        Command::TickRate(rate) => {
+            info!("Changing tick rate to {} Hz", rate);
+            *time_step = 1.0 / rate;
+        }
+        Command::Profile => {
+            for (id, game) in rooms.rooms() {
+                info!("Room {}:", id);
+                game.profile();
+            }
+        }
+        Command::Shutdown => {
+            rooms.shutdown(*time_step);
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn test_parse() {
+        let commands = [
+            ("", None),
+            ("   ", None),
+            ("help", Some(Command::Help)),
+            (" rooms ", Some(Command::Rooms)),
+            ("clients", Some(Command::Clients)),
+            ("kick 4294967297", Some(Command::Kick(4294967297))),
+            ("say hello   there ", Some(Command::Say("hello there".into()))),
+            ("asteroid 1 -2.5", Some(Command::SpawnAsteroid([1.0, -2.5], 0))),
+            ("ship 0 3 2", Some(Command::SpawnShip([0.0, 3.0], 2))),
+            ("tickrate 1", Some(Command::TickRate(1.0))),
+            ("tickrate 100", Some(Command::TickRate(100.0))),
+            ("profile", Some(Command::Profile)),
+            ("shutdown", Some(Command::Shutdown)),
+        ];
+        for &(line, ref command) in &commands {
+            assert_eq!(&Command::parse(line).unwrap(), command, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_bad_parse() {
+        let errors = [
+            ("jump", "Invalid command \"jump\""),
+            ("help me", "Invalid command \"help me\""),
+            ("say", "Invalid command \"say\""),
+            ("kick", "Invalid command \"kick\""),
+            ("kick -1", "Invalid number \"-1\""),
+            ("asteroid 1", "Usage: asteroid X Y [ROOM]"),
+            ("ship 1 2 3 4", "Usage: ship X Y [ROOM]"),
+            ("asteroid 1 y", "Invalid number \"y\""),
+            ("ship 1 2 -1", "Invalid number \"-1\""),
+            ("asteroid inf 0", "Invalid position"),
+            ("ship 0 NaN", "Invalid position"),
+            ("tickrate", "Invalid command \"tickrate\""),
+            ("tickrate fast", "Invalid number \"fast\""),
+            ("tickrate 0.5", "Tick rate should be between 1 and 100"),
+            ("tickrate 101", "Tick rate should be between 1 and 100"),
+        ];
+        for &(line, error) in &errors {
+            match Command::parse(line) {
+                Ok(c) => panic!("{:?} should be rejected, got {:?}", line, c),
+                Err(e) => assert!(e.starts_with(error), "{}", e),
+            }
+        }
+    }
+}
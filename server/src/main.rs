@@ -1,28 +1,78 @@
 //! Entrypoint and eventloop for server.
 
-use game::Game;
+mod config;
+mod console;
+mod transports;
+mod websocket;
+
+use game::net::rooms::Rooms;
 use game::net::udp::UdpServer;
 use log::{info, warn};
+use std::env;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::process;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
-const TIME_STEP: f32 = 0.080;
+use crate::config::{Config, USAGE};
+use crate::console::{execute, Console};
+use crate::transports::Transports;
+use crate::websocket::WebSocketServer;
 
 fn to_secs(dt: Duration) -> f32 {
     dt.as_secs() as f32 + dt.subsec_nanos() as f32 * 0.000_000_001
 }
 
+/// Exits if we couldn't listen on an address.
+fn listening<T, E: Display>(result: Result<T, E>, address: SocketAddr) -> T {
+    match result {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Couldn't listen on {}: {}", address, e);
+            process::exit(1);
+        }
+    }
+}
+
 /// Entrypoint for server.
 fn main() {
-    color_logger::init(log::Level::Info).unwrap();
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    color_logger::init(config.log_level).unwrap();
     info!("Starting up");
 
-    let mut game = Game::new_server(UdpServer::new(34244));
+    let udp_address = SocketAddr::new(config.address, config.port);
+    let ws_address = SocketAddr::new(config.address, config.websocket_port());
+    let server = Transports::new(
+        listening(UdpServer::bind(udp_address), udp_address),
+        listening(WebSocketServer::bind(ws_address), ws_address),
+    );
+    info!(
+        "Listening on {} (UDP) and {} (WebSocket)",
+        udp_address, ws_address
+    );
+    let mut rooms = Rooms::new(server, config.rooms_config());
+    let mut time_step = config.time_step();
+    let console = Console::start();
+    info!("Type \"help\" for the list of commands");
 
     let mut previous = SystemTime::now();
     let mut timer = 0.0;
 
     loop {
+        while let Some(command) = console.poll() {
+            if !execute(command, &mut rooms, &mut time_step) {
+                info!("Exiting");
+                return;
+            }
+        }
+
         let now = SystemTime::now();
 
         match now.duration_since(previous) {
@@ -30,19 +80,19 @@ fn main() {
                 let dt = to_secs(dt);
                 if dt > 0.5 {
                     warn!("Clock jumped forward by {} seconds!", dt);
-                    timer = 5.0 * TIME_STEP;
+                    timer = 5.0 * time_step;
                 } else {
                     timer += dt;
                 }
-                while timer > TIME_STEP {
-                    game.update(TIME_STEP);
-                    timer -= TIME_STEP;
+                while timer > time_step {
+                    rooms.update(time_step);
+                    timer -= time_step;
                 }
 
-                if TIME_STEP - timer > 0.001 {
+                if time_step - timer > 0.001 {
                     sleep(Duration::new(
                         0,
-                        ((TIME_STEP - timer) * 1_000_000_000.0) as u32,
+                        ((time_step - timer) * 1_000_000_000.0) as u32,
                     ));
                 }
             }
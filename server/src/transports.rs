@@ -0,0 +1,59 @@
+//! Serving the same game over several transports.
+
+use game::net::Server;
+use game::net::udp::UdpServer;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::websocket::WebSocketServer;
+
+/// Address of a client, on either transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    Udp(SocketAddr),
+    WebSocket(SocketAddr),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Udp(addr) => write!(f, "udp:{}", addr),
+            Address::WebSocket(addr) => write!(f, "ws:{}", addr),
+        }
+    }
+}
+
+/// Server taking both UDP clients and browsers over WebSocket.
+pub struct Transports {
+    udp: UdpServer,
+    websocket: WebSocketServer,
+}
+
+impl Transports {
+    pub fn new(udp: UdpServer, websocket: WebSocketServer) -> Transports {
+        Transports { udp, websocket }
+    }
+}
+
+impl Server for Transports {
+    type Address = Address;
+
+    fn send(&self, msg: &[u8], addr: &Address) -> io::Result<usize> {
+        match addr {
+            Address::Udp(addr) => self.udp.send(msg, addr),
+            Address::WebSocket(addr) => self.websocket.send(msg, addr),
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, Address)> {
+        match self.udp.recv(buffer) {
+            Ok((len, addr)) => return Ok((len, Address::Udp(addr))),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        self.websocket
+            .recv(buffer)
+            .map(|(len, addr)| (len, Address::WebSocket(addr)))
+    }
+}
@@ -0,0 +1,150 @@
+//! WebSocket transport, for clients running in the browser.
+//!
+//! Browsers can't use UDP, so the web client connects over WebSocket
+//! instead. Each binary WebSocket message holds one packet, so to the game
+//! this looks like a datagram socket that never loses anything.
+
+use game::net::Server;
+use log::{info, warn};
+use std::cell::RefCell;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::{Error, Message, WebSocket};
+
+type Handshake = MidHandshake<ServerHandshake<TcpStream, NoCallback>>;
+type HandshakeResult = Result<
+    WebSocket<TcpStream>,
+    HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+>;
+
+fn would_block(e: &Error) -> bool {
+    match e {
+        Error::Io(e) => e.kind() == io::ErrorKind::WouldBlock,
+        _ => false,
+    }
+}
+
+fn to_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    handshakes: Vec<(SocketAddr, Handshake)>,
+    sockets: Vec<(SocketAddr, WebSocket<TcpStream>)>,
+}
+
+impl Connections {
+    fn handshake_done(&mut self, addr: SocketAddr, result: HandshakeResult) {
+        match result {
+            Ok(socket) => {
+                info!("WebSocket connection from {}", addr);
+                self.sockets.push((addr, socket));
+            }
+            Err(HandshakeError::Interrupted(mid)) => {
+                self.handshakes.push((addr, mid));
+            }
+            Err(HandshakeError::Failure(e)) => {
+                info!("WebSocket handshake with {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+pub struct WebSocketServer {
+    listener: TcpListener,
+    connections: RefCell<Connections>,
+}
+
+impl WebSocketServer {
+    /// Listens on the given address.
+    pub fn bind(address: SocketAddr) -> io::Result<WebSocketServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(WebSocketServer {
+            listener,
+            connections: Default::default(),
+        })
+    }
+
+    /// Accepts new connections, and moves their handshakes along.
+    fn accept(&self, connections: &mut Connections) -> io::Result<()> {
+        loop {
+            let (stream, addr) = match self.listener.accept() {
+                Ok(c) => c,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("Couldn't set socket nonblocking: {}", e);
+                continue;
+            }
+            connections.handshake_done(addr, tungstenite::accept(stream));
+        }
+        for (addr, mid) in std::mem::take(&mut connections.handshakes) {
+            connections.handshake_done(addr, mid.handshake());
+        }
+        Ok(())
+    }
+}
+
+impl Server for WebSocketServer {
+    type Address = SocketAddr;
+
+    fn send(&self, msg: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let mut connections = self.connections.borrow_mut();
+        let idx = match connections.sockets.iter().position(|(a, _)| a == addr)
+        {
+            Some(i) => i,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        let socket = &mut connections.sockets[idx].1;
+        match socket.write_message(Message::Binary(msg.to_vec())) {
+            Ok(()) => Ok(msg.len()),
+            // Queued, it will go out as we read from the socket
+            Err(ref e) if would_block(e) => Ok(msg.len()),
+            Err(Error::SendQueueFull(_)) => {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(e) => {
+                info!("WebSocket connection from {} closed: {}", addr, e);
+                connections.sockets.remove(idx);
+                Err(to_io_error(e))
+            }
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut connections = self.connections.borrow_mut();
+        self.accept(&mut connections)?;
+
+        let mut i = 0;
+        while i < connections.sockets.len() {
+            let (addr, socket) = &mut connections.sockets[i];
+            let addr = *addr;
+            match socket.read_message() {
+                Ok(Message::Binary(data)) => {
+                    // Truncate like a datagram socket would
+                    let len = data.len().min(buffer.len());
+                    buffer[..len].copy_from_slice(&data[..len]);
+                    return Ok((len, addr));
+                }
+                // Text, pings, close; tungstenite answers those itself
+                Ok(_) => {}
+                Err(ref e) if would_block(e) => i += 1,
+                Err(e) => {
+                    info!("WebSocket connection from {} closed: {}", addr, e);
+                    connections.sockets.remove(i);
+                }
+            }
+        }
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
@@ -5,11 +5,11 @@
 //! when their number is low.
 
 use rand::prelude::*;
-use specs::{Component, Entities, Read, ReadExpect, Join, LazyUpdate,
-            NullStorage, ReadStorage, System};
+use specs::{Component, Entities, Entity, Read, ReadExpect, Join,
+            LazyUpdate, NullStorage, ReadStorage, System};
 use std::f32::consts::PI;
 
-use crate::Role;
+use crate::{Role, WorldConfig};
 use crate::blocks::{Block, BlockInner, Blocky};
 #[cfg(feature = "network")]
 use crate::net;
@@ -23,6 +23,59 @@ impl Component for Asteroid {
     type Storage = NullStorage<Self>;
 }
 
+impl Asteroid {
+    /// Creates an asteroid of random shape, at the given position and
+    /// velocity.
+    pub fn create(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        pos: [f32; 2],
+        vel: [f32; 2],
+    ) -> Entity {
+        let mut rng = rand::thread_rng();
+        // Generate blocks in an ellipse
+        let mut blocks = Vec::new();
+        let a = rng.gen_range(3.0, 4.0);
+        let ai = a as i32 + 1;
+        let b = rng.gen_range(2.0, 3.0);
+        let bi = b as i32 + 1;
+        for y in -ai..ai {
+            for x in -bi..bi {
+                let x = x as f32;
+                let y = y as f32;
+                if x * x * a * a + y * y * b * b <= a * a * b * b {
+                    blocks.push(([x, y], Block::new(BlockInner::Rock)));
+                }
+            }
+        }
+        let (blocky, _) = Blocky::new(blocks);
+
+        let entity = entities.create();
+        lazy.insert(
+            entity,
+            Position {
+                pos,
+                rot: rng.gen_range(0.0, 2.0 * PI),
+            },
+        );
+        lazy.insert(
+            entity,
+            Velocity {
+                vel,
+                rot: rng.gen_range(-2.0, 2.0),
+            },
+        );
+        lazy.insert(entity, Asteroid);
+        lazy.insert(entity, blocky);
+        #[cfg(feature = "network")]
+        {
+            lazy.insert(entity, net::Replicated::new());
+            lazy.insert(entity, net::Dirty);
+        }
+        entity
+    }
+}
+
 /// Asteroid spawning and removing.
 ///
 /// Asteroids are spawned after a delay when not enough exist, and removed on
@@ -32,6 +85,7 @@ pub struct SysAsteroid;
 impl<'a> System<'a> for SysAsteroid {
     type SystemData = (
         ReadExpect<'a, Role>,
+        Read<'a, WorldConfig>,
         Read<'a, LazyUpdate>,
         Entities<'a>,
         ReadStorage<'a, Position>,
@@ -40,25 +94,26 @@ impl<'a> System<'a> for SysAsteroid {
 
     fn run(
         &mut self,
-        (role, lazy, entities, pos, asteroid): Self::SystemData,
+        (role, world, lazy, entities, pos, asteroid): Self::SystemData,
     ) {
         assert!(role.authoritative());
 
         // Remove asteroids gone from the screen
+        let limit = world.size + 50.0;
         let mut count = 0;
         for (entity, pos, _) in (&*entities, &pos, &asteroid).join() {
             count += 1;
 
             let pos = pos.pos;
-            if pos[0] < -150.0 || pos[0] > 150.0 || pos[1] < -150.0
-                || pos[1] > 150.0
+            if pos[0] < -limit || pos[0] > limit || pos[1] < -limit
+                || pos[1] > limit
             {
                 delete_entity(*role, &entities, &lazy, entity);
                 continue;
             }
         }
 
-        if count < 60 {
+        if count < world.asteroids {
             // Choose position
             let mut rng = rand::thread_rng();
             let &(xpos, ypos) = [
@@ -67,51 +122,16 @@ impl<'a> System<'a> for SysAsteroid {
                 (0.0, -1.0), // bottom
                 (0.0, 1.0),  // top
             ].choose(&mut rng).unwrap();
-            // Generate blocks in an ellipse
-            let mut blocks = Vec::new();
-            let a = rng.gen_range(3.0, 4.0);
-            let ai = a as i32 + 1;
-            let b = rng.gen_range(2.0, 3.0);
-            let bi = b as i32 + 1;
-            for y in -ai..ai {
-                for x in -bi..bi {
-                    let x = x as f32;
-                    let y = y as f32;
-                    if x * x * a * a + y * y * b * b <= a * a * b * b {
-                        blocks.push(([x, y], Block::new(BlockInner::Rock)));
-                    }
-                }
-            }
-            let (blocky, _) = Blocky::new(blocks);
-
-            let entity = entities.create();
-            lazy.insert(
-                entity,
-                Position {
-                    pos: [
-                        xpos * 145.0 + ypos * rng.gen_range(-140.0, 140.0),
-                        ypos * 145.0 + xpos * rng.gen_range(-140.0, 140.0),
-                    ],
-                    rot: rng.gen_range(0.0, 2.0 * PI),
-                },
-            );
-            lazy.insert(
-                entity,
-                Velocity {
-                    vel: [
-                        rng.gen_range(-4.0, 4.0) - xpos * 10.0,
-                        rng.gen_range(-4.0, 4.0) - ypos * 10.0,
-                    ],
-                    rot: rng.gen_range(-2.0, 2.0),
-                },
-            );
-            lazy.insert(entity, Asteroid);
-            lazy.insert(entity, blocky);
-            #[cfg(feature = "network")]
-            {
-                lazy.insert(entity, net::Replicated::new());
-                lazy.insert(entity, net::Dirty);
-            }
+            let spread = limit - 10.0;
+            let pos = [
+                xpos * (limit - 5.0) + ypos * rng.gen_range(-spread, spread),
+                ypos * (limit - 5.0) + xpos * rng.gen_range(-spread, spread),
+            ];
+            let vel = [
+                rng.gen_range(-4.0, 4.0) - xpos * 10.0,
+                rng.gen_range(-4.0, 4.0) - ypos * 10.0,
+            ];
+            Asteroid::create(&entities, &lazy, pos, vel);
         }
     }
 }
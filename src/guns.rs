@@ -12,6 +12,7 @@ use crate::particles::{Effect, EffectInner};
 use crate::physics::{affect_area, delete_entity, AABox, DetectCollision,
                      HitEffect, Hits, Position, Velocity};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ProjectileType {
     Plasma,
     Rail,
@@ -132,6 +132,24 @@ impl Deref for Clock {
     }
 }
 
+/// Settings of the simulated world, available as a resource.
+#[derive(Debug, Clone)]
+pub struct WorldConfig {
+    /// Half the width of the square area ships are kept in.
+    pub size: f32,
+    /// Number of asteroids kept floating around.
+    pub asteroids: usize,
+}
+
+impl Default for WorldConfig {
+    fn default() -> WorldConfig {
+        WorldConfig {
+            size: 100.0,
+            asteroids: 60,
+        }
+    }
+}
+
 /// The game structure, containing globals not specific to frontend.
 pub struct Game {
     pub world: World,
@@ -158,11 +176,14 @@ impl Game {
             world.register::<net::Dirty>();
             world.register::<net::Delete>();
             world.register::<net::ClientControlled>();
+            world.register::<net::Snapshots>();
+            world.register::<net::PositionHistory>();
         }
 
         world.insert(DeltaTime(0.02));
         world.insert(<Clock as Default>::default());
         world.insert(<Input as Default>::default());
+        world.insert(<WorldConfig as Default>::default());
         world.insert(role);
 
         let dispatcher = if role.authoritative() {
@@ -205,11 +226,18 @@ impl Game {
     }
 
     #[cfg(feature = "network")]
-    pub fn new_server<S: net::Server>(server: S) -> Game {
-        let (world, mut dispatcher) = Self::new_common(Role::Server);
+    pub fn new_server<S: net::Server>(
+        server: S,
+        config: net::ServerConfig,
+    ) -> Game {
+        let (mut world, mut dispatcher) = Self::new_common(Role::Server);
+        world.insert(net::Rejected::default());
+        world.insert(net::NetStats::default());
+        world.insert(net::Admin::default());
+        world.insert(net::Chat::default());
 
         dispatcher = dispatcher.with(
-            net::SysNetServer::new(server),
+            net::SysNetServer::new(server, config),
             "netserver",
             &[],
         );
@@ -221,11 +249,16 @@ impl Game {
     }
 
     #[cfg(feature = "network")]
-    pub fn new_client<C: net::Client>(client: C) -> Game {
-        let (world, mut dispatcher) = Self::new_common(Role::Client);
+    pub fn new_client<C: net::Client>(
+        client: C,
+        config: net::ClientConfig,
+    ) -> Game {
+        let (mut world, mut dispatcher) = Self::new_common(Role::Client);
+        world.insert(net::NetStats::default());
+        world.insert(net::Chat::default());
 
         dispatcher = dispatcher.with(
-            net::SysNetClient::new(client),
+            net::SysNetClient::new(client, config),
             "netclient",
             &[],
         );
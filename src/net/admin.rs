@@ -0,0 +1,47 @@
+//! Control over the network server, for its operator.
+
+/// A connected client, as shown to the operator.
+#[derive(Debug, Clone)]
+pub struct ClientSummary {
+    pub client_id: u64,
+    pub name: String,
+    pub address: String,
+    /// Round-trip time, in seconds, 0 until measured.
+    pub rtt: f32,
+    /// Number of ships it controls.
+    pub ships: usize,
+    /// Whether it is only watching.
+    pub spectator: bool,
+}
+
+/// Something the operator asked the server to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum AdminCommand {
+    /// Disconnect a client, deleting its ships.
+    Kick(u64),
+    /// Disconnect everyone, as the server is going away.
+    Shutdown,
+}
+
+/// Lets the operator see and act on clients, available as a resource on
+/// the server.
+///
+/// Commands are carried out on the next update.
+#[derive(Debug, Default)]
+pub struct Admin {
+    /// Connected clients, as of the last update.
+    pub clients: Vec<ClientSummary>,
+    /// Clients that timed out, whose ships are kept for them to reconnect.
+    pub reconnecting: usize,
+    pub(super) commands: Vec<AdminCommand>,
+}
+
+impl Admin {
+    pub fn kick(&mut self, client_id: u64) {
+        self.commands.push(AdminCommand::Kick(client_id));
+    }
+
+    pub fn shutdown(&mut self) {
+        self.commands.push(AdminCommand::Shutdown);
+    }
+}
@@ -1,16 +1,18 @@
 use specs::{Component, HashMapStorage, NullStorage, VecStorage};
+use std::num::Wrapping;
 
 /// Replicated entities have an id to match them on multiple machines.
 pub struct Replicated {
     pub id: u64,
-    pub last_update: u32,
+    /// Revision of the `Blocky` structure last sent, if any.
+    pub blocky_revision: Option<Wrapping<u32>>,
 }
 
 impl Replicated {
     pub fn new() -> Replicated {
         Replicated {
             id: 0,
-            last_update: 0,
+            blocky_revision: None,
         }
     }
 }
@@ -19,6 +21,12 @@ impl Component for Replicated {
     type Storage = VecStorage<Self>;
 }
 
+impl Default for Replicated {
+    fn default() -> Replicated {
+        Replicated::new()
+    }
+}
+
 /// Mark an entity to be deleted everywhere.
 #[derive(Default)]
 pub struct Delete;
@@ -40,6 +48,10 @@ impl Component for Dirty {
 /// Multiple entities can be controlled by the same client, and that's fine.
 pub struct ClientControlled {
     pub client_id: u64,
+    /// Sequence number of the last input from the client that was applied.
+    pub last_input: u32,
+    /// Server tick the client was showing when it sent that input.
+    pub view_tick: u32,
 }
 
 impl Component for ClientControlled {
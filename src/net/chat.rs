@@ -0,0 +1,132 @@
+//! Text chat between players.
+//!
+//! Clients send what their player typed with `Message::ChatSend`, and the
+//! server checks it and passes it on to everyone in the room with the
+//! sender's name, as `Message::Chat`. Both sides keep what was said
+//! in the `Chat` resource.
+
+use std::collections::{HashSet, VecDeque};
+
+/// Most messages kept in the log.
+const MAX_LOG: usize = 50;
+
+/// A chat message, as shown to players.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatLine {
+    pub sender: String,
+    pub text: String,
+}
+
+/// Chat messages received, and the ones to send, available as a resource.
+///
+/// On the server, messages sent come from the operator. They go out on the
+/// next update.
+#[derive(Debug, Default)]
+pub struct Chat {
+    /// Latest messages, oldest first.
+    pub log: VecDeque<ChatLine>,
+    pub(super) outgoing: Vec<String>,
+}
+
+impl Chat {
+    pub fn send(&mut self, text: &str) {
+        self.outgoing.push(text.to_owned());
+    }
+
+    /// Adds a message to the log, forgetting the oldest ones.
+    pub(super) fn record(&mut self, sender: String, text: String) {
+        if self.log.len() >= MAX_LOG {
+            self.log.pop_front();
+        }
+        self.log.push_back(ChatLine { sender, text });
+    }
+}
+
+/// Checks what players say, before the server shows it to others, e.g. to
+/// keep out words that aren't welcome.
+///
+/// Applies to chat messages and player names.
+pub trait ChatFilter: Send + Sync {
+    /// Returns the text to show, possibly changed, or `None` to drop it.
+    fn filter(&self, text: &str) -> Option<String>;
+}
+
+/// Masks words from a list, ignoring case.
+pub struct WordFilter {
+    words: HashSet<String>,
+}
+
+impl WordFilter {
+    pub fn new<I, S>(words: I) -> WordFilter
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        WordFilter {
+            words: words
+                .into_iter()
+                .map(|w| w.as_ref().trim().to_lowercase())
+                .filter(|w| !w.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl ChatFilter for WordFilter {
+    fn filter(&self, text: &str) -> Option<String> {
+        let mut filtered = String::with_capacity(text.len());
+        let mut word = String::new();
+        let flush = |word: &mut String, filtered: &mut String| {
+            if self.words.contains(&word.to_lowercase()) {
+                filtered.extend(word.chars().map(|_| '*'));
+            } else {
+                filtered.push_str(word);
+            }
+            word.clear();
+        };
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut filtered);
+                filtered.push(c);
+            }
+        }
+        flush(&mut word, &mut filtered);
+        Some(filtered)
+    }
+}
+
+/// Cleans up text typed by a player: control characters become spaces,
+/// whitespace around goes, and it is cut to `max_len` characters.
+pub(super) fn sanitize(text: &str, max_len: usize) -> String {
+    let text: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let text: String = text.trim().chars().take(max_len).collect();
+    text.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize, ChatFilter, WordFilter};
+
+    #[test]
+    fn test_word_filter() {
+        let filter = WordFilter::new(vec!["darn", " Heck "]);
+        assert_eq!(
+            filter.filter("Darn it, what the heck!").as_deref(),
+            Some("**** it, what the ****!")
+        );
+        // Only whole words
+        assert_eq!(filter.filter("darnation").as_deref(), Some("darnation"));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("  hi\nthere\t ", 100), "hi there");
+        assert_eq!(sanitize("héllo world", 6), "héllo");
+        assert_eq!(sanitize("\u{7}", 100), "");
+    }
+}
@@ -0,0 +1,1505 @@
+//! Wire protocol: the messages exchanged by server and clients, and how they
+//! are turned into bytes and back.
+//!
+//! Nothing in here panics on bad input: every malformed, truncated or
+//! out-of-range message is reported as a `DecodeError`, so that a single bad
+//! datagram can't take down a server or client.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::error::Error;
+use std::fmt;
+use std::f32::consts::PI;
+use std::io::{self, Cursor, Read};
+use std::num::Wrapping;
+use std::time::Duration;
+use vecmath::vec2_square_len;
+
+use crate::blocks::{Block, BlockInner, Blocky};
+use crate::guns::ProjectileType;
+use crate::particles::EffectInner;
+use crate::physics::{Position, Velocity};
+use crate::ship::Ship;
+use crate::utils::angle_wrap;
+
+use super::ORDER;
+
+/// Magic bytes starting every message.
+const MAGIC: &[u8] = b"SPAC\x00\x01";
+
+/// Version of the protocol spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest version of the protocol this build can still speak.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Biggest explosion we accept to render.
+const MAX_EXPLOSION_SIZE: f32 = 16.0;
+
+/// Furthest a ship can aim, from its own position.
+const MAX_TARGET_DISTANCE: f32 = 1000.0;
+
+/// Farthest a block can be from the center of its structure.
+const MAX_BLOCK_DISTANCE: f32 = 256.0;
+
+/// Longest server, map or player name, in bytes.
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Longest chat message, in bytes.
+pub const MAX_CHAT_LENGTH: usize = 512;
+
+/// Picks the version to use with a client, or None if we can't talk to it.
+pub fn negotiate_version(client_version: u16) -> Option<u16> {
+    if client_version < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(client_version.min(PROTOCOL_VERSION))
+    }
+}
+
+/// Error decoding a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// Message doesn't start with the protocol's magic bytes.
+    BadMagic,
+    /// Message type is not known.
+    UnknownMessage([u8; 2]),
+    /// Message ended before all its fields could be read.
+    Truncated,
+    /// Message has extra bytes after its fields.
+    TrailingBytes,
+    /// A field has a value that is not allowed.
+    InvalidValue(&'static str),
+    /// State was written against a baseline we don't have.
+    MissingBaseline,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::BadMagic => write!(f, "bad magic"),
+            DecodeError::UnknownMessage(t) => {
+                write!(f, "unknown message type {:?}", t)
+            }
+            DecodeError::Truncated => write!(f, "truncated message"),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes"),
+            DecodeError::InvalidValue(what) => write!(f, "invalid {}", what),
+            DecodeError::MissingBaseline => write!(f, "missing baseline"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    /// Reading from a slice only fails when running out of bytes.
+    fn from(_: io::Error) -> DecodeError {
+        DecodeError::Truncated
+    }
+}
+
+/// Reason given by the server for closing a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// No common protocol version.
+    VersionMismatch,
+    /// Too many clients connected already.
+    ServerFull,
+    /// Sent away by the server's operator.
+    Kicked,
+    /// The server is going away.
+    ShuttingDown,
+    /// The session the client tried to use is gone, e.g. it took too long
+    /// to reconnect.
+    UnknownSession,
+    /// The client stopped acknowledging what it was sent.
+    TooSlow,
+}
+
+impl DisconnectReason {
+    fn code(self) -> u8 {
+        match self {
+            DisconnectReason::VersionMismatch => 1,
+            DisconnectReason::ServerFull => 2,
+            DisconnectReason::Kicked => 3,
+            DisconnectReason::ShuttingDown => 4,
+            DisconnectReason::UnknownSession => 5,
+            DisconnectReason::TooSlow => 6,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<DisconnectReason, DecodeError> {
+        match code {
+            1 => Ok(DisconnectReason::VersionMismatch),
+            2 => Ok(DisconnectReason::ServerFull),
+            3 => Ok(DisconnectReason::Kicked),
+            4 => Ok(DisconnectReason::ShuttingDown),
+            5 => Ok(DisconnectReason::UnknownSession),
+            6 => Ok(DisconnectReason::TooSlow),
+            _ => Err(DecodeError::InvalidValue("disconnect reason")),
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::VersionMismatch => {
+                write!(f, "incompatible protocol version")
+            }
+            DisconnectReason::ServerFull => write!(f, "server is full"),
+            DisconnectReason::Kicked => write!(f, "kicked from the server"),
+            DisconnectReason::ShuttingDown => {
+                write!(f, "server is shutting down")
+            }
+            DisconnectReason::UnknownSession => write!(f, "session expired"),
+            DisconnectReason::TooSlow => write!(f, "connection too slow"),
+        }
+    }
+}
+
+pub fn time_encode(d: Duration) -> u32 {
+    (d.as_secs() as u32).wrapping_shl(10) | d.subsec_nanos().wrapping_shr(22)
+}
+
+pub fn time_decode(b: u32) -> Duration {
+    let secs = (b as u64).wrapping_shr(10);
+    let nanos = b.wrapping_shl(22);
+    Duration::new(secs, nanos)
+}
+
+pub fn write_float(writer: &mut Vec<u8>, v: f32) {
+    writer.write_f32::<ORDER>(v).unwrap();
+}
+
+/// Reads a float, which has to be finite.
+pub fn read_float<R: Read>(reader: &mut R) -> Result<f32, DecodeError> {
+    let v = reader.read_f32::<ORDER>()?;
+    if v.is_finite() {
+        Ok(v)
+    } else {
+        Err(DecodeError::InvalidValue("float"))
+    }
+}
+
+/// Writes a string, preceded by its length, cut to at most `max_len` bytes.
+pub fn write_string(writer: &mut Vec<u8>, s: &str, max_len: usize) {
+    let mut len = s.len().min(max_len);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    writer.write_u16::<ORDER>(len as u16).unwrap();
+    writer.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Reads a string, which has to be valid UTF-8 of at most `max_len` bytes.
+pub fn read_string<R: Read>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<String, DecodeError> {
+    let len = reader.read_u16::<ORDER>()? as usize;
+    if len > max_len {
+        return Err(DecodeError::InvalidValue("string length"));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| DecodeError::InvalidValue("string"))
+}
+
+/// Description of a server, given to clients looking for one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub players: u16,
+    pub max_players: u16,
+    pub map: String,
+    /// Newest protocol version the server speaks.
+    pub protocol_version: u16,
+    /// Oldest protocol version the server speaks.
+    pub min_protocol_version: u16,
+}
+
+impl ServerInfo {
+    /// Whether we can join that server.
+    pub fn compatible(&self) -> bool {
+        self.min_protocol_version <= PROTOCOL_VERSION
+            && self.protocol_version >= MIN_PROTOCOL_VERSION
+    }
+
+    fn write(&self, writer: &mut Vec<u8>) {
+        write_string(writer, &self.name, MAX_NAME_LENGTH);
+        writer.write_u16::<ORDER>(self.players).unwrap();
+        writer.write_u16::<ORDER>(self.max_players).unwrap();
+        write_string(writer, &self.map, MAX_NAME_LENGTH);
+        writer.write_u16::<ORDER>(self.protocol_version).unwrap();
+        writer.write_u16::<ORDER>(self.min_protocol_version).unwrap();
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<ServerInfo, DecodeError> {
+        Ok(ServerInfo {
+            name: read_string(reader, MAX_NAME_LENGTH)?,
+            players: reader.read_u16::<ORDER>()?,
+            max_players: reader.read_u16::<ORDER>()?,
+            map: read_string(reader, MAX_NAME_LENGTH)?,
+            protocol_version: reader.read_u16::<ORDER>()?,
+            min_protocol_version: reader.read_u16::<ORDER>()?,
+        })
+    }
+}
+
+/// Units per world unit, for positions, velocities and ship controls.
+const LINEAR_SCALE: f32 = 256.0;
+
+/// Units per radian per second, for angular velocities.
+const ANGULAR_SCALE: f32 = 1024.0;
+
+/// Units per full turn, for rotations.
+const TURN_SCALE: f32 = 65536.0;
+
+/// Number of integer fields in a `QuantizedState`.
+const STATE_FIELDS: usize = 18;
+
+/// Fields used by each component: mask bit, first field, number of fields.
+const COMPONENT_FIELDS: [(u16, usize, usize); 5] = [
+    (COMPONENT_POSITION, 0, 3),
+    (COMPONENT_VELOCITY, 3, 3),
+    (COMPONENT_SHIP, 6, 8),
+    (COMPONENT_PROJECTILE, 14, 3),
+    (COMPONENT_INPUT_ACK, 17, 1),
+];
+
+fn quantize(v: f32, scale: f32) -> i32 {
+    // Casting saturates, and turns NaN into 0
+    (v * scale).round() as i32
+}
+
+fn dequantize(v: i32, scale: f32) -> f32 {
+    v as f32 / scale
+}
+
+fn write_varint(writer: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        writer.write_u8(v as u8 | 0x80).unwrap();
+        v >>= 7;
+    }
+    writer.write_u8(v as u8).unwrap();
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u32, DecodeError> {
+    let mut v = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = reader.read_u8()?;
+        if shift == 28 && byte > 0x0F {
+            return Err(DecodeError::InvalidValue("varint"));
+        }
+        v |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(DecodeError::InvalidValue("varint"))
+}
+
+/// Maps signed to unsigned so that small magnitudes give small varints.
+fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn unzigzag(v: u32) -> i32 {
+    (v >> 1) as i32 ^ -((v & 1) as i32)
+}
+
+/// Checks that a reader has been consumed entirely.
+fn check_end(reader: &Cursor<&[u8]>) -> Result<(), DecodeError> {
+    if reader.position() as usize == reader.get_ref().len() {
+        Ok(())
+    } else {
+        Err(DecodeError::TrailingBytes)
+    }
+}
+
+/// Kind of a replicated entity, telling clients what to create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityKind {
+    /// A ship, possibly controlled by a player.
+    Ship,
+    /// An asteroid, or a piece that broke off one.
+    Asteroid,
+    /// A piece that broke off a ship.
+    Debris,
+    /// A projectile.
+    Projectile,
+}
+
+impl EntityKind {
+    fn code(self) -> u8 {
+        match self {
+            EntityKind::Ship => 1,
+            EntityKind::Asteroid => 2,
+            EntityKind::Debris => 3,
+            EntityKind::Projectile => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<EntityKind, DecodeError> {
+        match code {
+            1 => Ok(EntityKind::Ship),
+            2 => Ok(EntityKind::Asteroid),
+            3 => Ok(EntityKind::Debris),
+            4 => Ok(EntityKind::Projectile),
+            _ => Err(DecodeError::InvalidValue("entity kind")),
+        }
+    }
+
+    /// Components an entity of this kind can't be created without.
+    pub fn required_components(self) -> u16 {
+        let base = COMPONENT_POSITION | COMPONENT_VELOCITY;
+        match self {
+            EntityKind::Ship => base | COMPONENT_SHIP,
+            EntityKind::Asteroid | EntityKind::Debris => base,
+            EntityKind::Projectile => base | COMPONENT_PROJECTILE,
+        }
+    }
+}
+
+/// Component mask bit for `Position`.
+pub const COMPONENT_POSITION: u16 = 0x0001;
+/// Component mask bit for `Velocity`.
+pub const COMPONENT_VELOCITY: u16 = 0x0002;
+/// Component mask bit for `Ship`.
+pub const COMPONENT_SHIP: u16 = 0x0004;
+/// Component mask bit for `Projectile`.
+pub const COMPONENT_PROJECTILE: u16 = 0x0008;
+/// Component mask bit for the last input applied, only sent to the client
+/// controlling the entity.
+pub const COMPONENT_INPUT_ACK: u16 = 0x0010;
+
+/// All the component bits this version knows how to read.
+const KNOWN_COMPONENTS: u16 = COMPONENT_POSITION
+    | COMPONENT_VELOCITY
+    | COMPONENT_SHIP
+    | COMPONENT_PROJECTILE
+    | COMPONENT_INPUT_ACK;
+
+/// Replicated components of an entity, sent by the server in `EntitySpawn`
+/// and `EntityUpdate` as a `QuantizedState`.
+#[derive(Default)]
+pub struct EntityState {
+    pub position: Option<Position>,
+    pub velocity: Option<Velocity>,
+    pub ship: Option<Ship>,
+    /// Kind of projectile, and ID of the entity that fired it.
+    pub projectile: Option<(ProjectileType, u64)>,
+    /// Sequence number of the last input from the controlling client that
+    /// went into this state.
+    pub input_ack: Option<u32>,
+}
+
+impl EntityState {
+    /// Mask of the components present.
+    pub fn components(&self) -> u16 {
+        let mut mask = 0;
+        if self.position.is_some() {
+            mask |= COMPONENT_POSITION;
+        }
+        if self.velocity.is_some() {
+            mask |= COMPONENT_VELOCITY;
+        }
+        if self.ship.is_some() {
+            mask |= COMPONENT_SHIP;
+        }
+        if self.projectile.is_some() {
+            mask |= COMPONENT_PROJECTILE;
+        }
+        if self.input_ack.is_some() {
+            mask |= COMPONENT_INPUT_ACK;
+        }
+        mask
+    }
+
+    /// Rounds the state to what can be sent.
+    pub fn quantize(&self) -> QuantizedState {
+        let mut v = [0; STATE_FIELDS];
+        if let Some(ref pos) = self.position {
+            v[0] = quantize(pos.pos[0], LINEAR_SCALE);
+            v[1] = quantize(pos.pos[1], LINEAR_SCALE);
+            v[2] = quantize(angle_wrap(pos.rot) / (2.0 * PI), TURN_SCALE);
+        }
+        if let Some(ref vel) = self.velocity {
+            v[3] = quantize(vel.vel[0], LINEAR_SCALE);
+            v[4] = quantize(vel.vel[1], LINEAR_SCALE);
+            v[5] = quantize(vel.rot, ANGULAR_SCALE);
+        }
+        if let Some(ref ship) = self.ship {
+            let fields = [
+                ship.want_thrust[0],
+                ship.want_thrust[1],
+                ship.want_thrust_rot,
+                ship.want_target[0],
+                ship.want_target[1],
+                ship.thrust[0],
+                ship.thrust[1],
+                ship.thrust_rot,
+            ];
+            for (i, &f) in fields.iter().enumerate() {
+                v[6 + i] = quantize(f, LINEAR_SCALE);
+            }
+        }
+        if let Some((kind, shooter)) = self.projectile {
+            v[14] = match kind {
+                ProjectileType::Plasma => 1,
+                ProjectileType::Rail => 2,
+            };
+            v[15] = (shooter >> 32) as i32;
+            v[16] = shooter as i32;
+        }
+        if let Some(seq) = self.input_ack {
+            v[17] = seq as i32;
+        }
+        QuantizedState {
+            mask: self.components(),
+            values: v,
+        }
+    }
+}
+
+/// Entity state with its fields rounded to integers, as sent on the wire.
+///
+/// States are written as the difference from a baseline, a previous state
+/// of the entity the client said it received, so that fields that don't
+/// change take a single byte. On the wire, the age of the baseline in ticks
+/// comes first (0 for none), then a mask of the components present, then
+/// the fields of each present component in the order of their mask bits, as
+/// variable-length integers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizedState {
+    mask: u16,
+    values: [i32; STATE_FIELDS],
+}
+
+impl QuantizedState {
+    /// Gets the values back.
+    pub fn state(&self) -> EntityState {
+        let v = &self.values;
+        let mut state = EntityState::default();
+        if self.mask & COMPONENT_POSITION != 0 {
+            state.position = Some(Position {
+                pos: [
+                    dequantize(v[0], LINEAR_SCALE),
+                    dequantize(v[1], LINEAR_SCALE),
+                ],
+                rot: dequantize(v[2], TURN_SCALE) * 2.0 * PI,
+            });
+        }
+        if self.mask & COMPONENT_VELOCITY != 0 {
+            state.velocity = Some(Velocity {
+                vel: [
+                    dequantize(v[3], LINEAR_SCALE),
+                    dequantize(v[4], LINEAR_SCALE),
+                ],
+                rot: dequantize(v[5], ANGULAR_SCALE),
+            });
+        }
+        if self.mask & COMPONENT_SHIP != 0 {
+            let f = |i: usize| dequantize(v[i], LINEAR_SCALE);
+            let mut ship = Ship::new();
+            ship.want_thrust = [f(6), f(7)];
+            ship.want_thrust_rot = f(8);
+            ship.want_target = [f(9), f(10)];
+            ship.thrust = [f(11), f(12)];
+            ship.thrust_rot = f(13);
+            state.ship = Some(ship);
+        }
+        if self.mask & COMPONENT_PROJECTILE != 0 {
+            // Checked by read()
+            let kind = if v[14] == 2 {
+                ProjectileType::Rail
+            } else {
+                ProjectileType::Plasma
+            };
+            let shooter = (v[15] as u32 as u64) << 32 | v[16] as u32 as u64;
+            state.projectile = Some((kind, shooter));
+        }
+        if self.mask & COMPONENT_INPUT_ACK != 0 {
+            state.input_ack = Some(v[17] as u32);
+        }
+        state
+    }
+
+    /// Writes the state, as the difference from a baseline state that is
+    /// the given number of ticks older.
+    pub fn write(
+        &self,
+        writer: &mut Vec<u8>,
+        baseline: Option<(u32, &QuantizedState)>,
+    ) {
+        write_varint(writer, baseline.map_or(0, |(age, _)| age));
+        writer.write_u16::<ORDER>(self.mask).unwrap();
+        let baseline = baseline.map(|(_, b)| b);
+        for &(bit, first, count) in &COMPONENT_FIELDS {
+            if self.mask & bit == 0 {
+                continue;
+            }
+            let base = baseline.filter(|b| b.mask & bit != 0);
+            for i in first..first + count {
+                let old = base.map_or(0, |b| b.values[i]);
+                write_varint(writer, zigzag(self.values[i].wrapping_sub(old)));
+            }
+        }
+    }
+
+    /// Gets the age of the baseline a state was written against, or 0 if it
+    /// was written in full.
+    pub fn baseline_age(data: &[u8]) -> Result<u32, DecodeError> {
+        read_varint(&mut Cursor::new(data))
+    }
+
+    /// Reads a state, given the baseline it was written against.
+    pub fn read(
+        data: &[u8],
+        baseline: Option<&QuantizedState>,
+    ) -> Result<QuantizedState, DecodeError> {
+        let mut rdr = Cursor::new(data);
+        let baseline = match (read_varint(&mut rdr)?, baseline) {
+            (0, _) => None,
+            (_, Some(b)) => Some(b),
+            (_, None) => return Err(DecodeError::MissingBaseline),
+        };
+        let mask = rdr.read_u16::<ORDER>()?;
+        if mask & !KNOWN_COMPONENTS != 0 {
+            return Err(DecodeError::InvalidValue("component mask"));
+        }
+        let mut values = [0; STATE_FIELDS];
+        for &(bit, first, count) in &COMPONENT_FIELDS {
+            if mask & bit == 0 {
+                continue;
+            }
+            let base = baseline.filter(|b| b.mask & bit != 0);
+            for (i, value) in
+                values.iter_mut().enumerate().skip(first).take(count)
+            {
+                let old = base.map_or(0, |b| b.values[i]);
+                *value = old.wrapping_add(unzigzag(read_varint(&mut rdr)?));
+            }
+        }
+        if mask & COMPONENT_PROJECTILE != 0 && !(1..=2).contains(&values[14])
+        {
+            return Err(DecodeError::InvalidValue("projectile type"));
+        }
+        check_end(&rdr)?;
+        Ok(QuantizedState { mask, values })
+    }
+}
+
+/// Controls of a ship, sent by the client in `EntityUpdate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Controls {
+    pub fire: bool,
+    pub thrust: [f32; 2],
+    pub thrust_rot: f32,
+    pub target: [f32; 2],
+    /// Server tick the client was showing, to check its shots against what
+    /// it saw.
+    pub view_tick: u32,
+}
+
+impl Controls {
+    pub fn from_ship(ship: &Ship, view_tick: u32) -> Controls {
+        Controls {
+            fire: ship.want_fire,
+            thrust: ship.want_thrust,
+            thrust_rot: ship.want_thrust_rot,
+            target: ship.want_target,
+            view_tick,
+        }
+    }
+
+    /// Sets a ship's controls.
+    pub fn apply(&self, ship: &mut Ship) {
+        ship.want_fire = self.fire;
+        ship.want_thrust = self.thrust;
+        ship.want_thrust_rot = self.thrust_rot;
+        ship.want_target = self.target;
+    }
+
+    /// Writes the controls, quantizing thrust to a set of flags.
+    pub fn write(&self, writer: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.fire {
+            flags |= 0x01;
+        }
+        if self.thrust[0] > 0.5 {
+            flags |= 0x02;
+        } else if self.thrust[0] < -0.5 {
+            flags |= 0x04;
+        }
+        if self.thrust[1] > 0.5 {
+            flags |= 0x08;
+        }
+        if self.thrust_rot > 0.5 {
+            flags |= 0x10;
+        } else if self.thrust_rot < -0.5 {
+            flags |= 0x20;
+        }
+        writer.write_u8(flags).unwrap();
+        write_float(writer, self.target[0]);
+        write_float(writer, self.target[1]);
+        writer.write_u32::<ORDER>(self.view_tick).unwrap();
+    }
+
+    pub fn read(data: &[u8]) -> Result<Controls, DecodeError> {
+        let mut rdr = Cursor::new(data);
+        let flags = rdr.read_u8()?;
+        if flags & 0xC0 != 0 || flags & 0x06 == 0x06 || flags & 0x30 == 0x30
+        {
+            return Err(DecodeError::InvalidValue("control flags"));
+        }
+        let controls = Controls {
+            fire: flags & 0x01 == 0x01,
+            thrust: [
+                match flags & 0x06 {
+                    0x02 => 1.0,
+                    0x04 => -1.0,
+                    _ => 0.0,
+                },
+                if flags & 0x08 == 0x08 { 1.0 } else { 0.0 },
+            ],
+            thrust_rot: match flags & 0x30 {
+                0x10 => 1.0,
+                0x20 => -1.0,
+                _ => 0.0,
+            },
+            target: [read_float(&mut rdr)?, read_float(&mut rdr)?],
+            view_tick: rdr.read_u32::<ORDER>()?,
+        };
+        check_end(&rdr)?;
+        if vec2_square_len(controls.target)
+            > MAX_TARGET_DISTANCE * MAX_TARGET_DISTANCE
+        {
+            return Err(DecodeError::InvalidValue("control target"));
+        }
+        Ok(controls)
+    }
+}
+
+/// Writes the structure of a `Blocky` object: revision, then each block's
+/// location, health, type and parameters.
+pub fn write_blocky(writer: &mut Vec<u8>, blocky: &Blocky) {
+    writer.write_u32::<ORDER>(blocky.revision.0).unwrap();
+    writer
+        .write_u16::<ORDER>(blocky.blocks.len() as u16)
+        .unwrap();
+    for &(loc, ref block) in &blocky.blocks {
+        write_float(writer, loc[0]);
+        write_float(writer, loc[1]);
+        write_float(writer, block.health);
+        match block.inner {
+            BlockInner::Cockpit => writer.write_u8(1).unwrap(),
+            BlockInner::Thruster { angle } => {
+                writer.write_u8(2).unwrap();
+                write_float(writer, angle);
+            }
+            BlockInner::PlasmaGun { angle, cooldown } => {
+                writer.write_u8(3).unwrap();
+                write_float(writer, angle);
+                write_float(writer, cooldown);
+            }
+            BlockInner::RailGun { angle, cooldown } => {
+                writer.write_u8(4).unwrap();
+                write_float(writer, angle);
+                write_float(writer, cooldown);
+            }
+            BlockInner::Armor => writer.write_u8(5).unwrap(),
+            BlockInner::Rock => writer.write_u8(6).unwrap(),
+        }
+    }
+}
+
+/// Reads a `Blocky` object written by `write_blocky()`.
+pub fn read_blocky(data: &[u8]) -> Result<Blocky, DecodeError> {
+    let mut rdr = Cursor::new(data);
+    let revision = rdr.read_u32::<ORDER>()?;
+    let len = rdr.read_u16::<ORDER>()?;
+    let mut blocks = Vec::new();
+    for _ in 0..len {
+        let loc = [read_float(&mut rdr)?, read_float(&mut rdr)?];
+        if loc[0].abs() > MAX_BLOCK_DISTANCE
+            || loc[1].abs() > MAX_BLOCK_DISTANCE
+        {
+            return Err(DecodeError::InvalidValue("block location"));
+        }
+        let health = read_float(&mut rdr)?;
+        let inner = match rdr.read_u8()? {
+            1 => BlockInner::Cockpit,
+            2 => BlockInner::Thruster {
+                angle: read_float(&mut rdr)?,
+            },
+            3 => BlockInner::PlasmaGun {
+                angle: read_float(&mut rdr)?,
+                cooldown: read_float(&mut rdr)?,
+            },
+            4 => BlockInner::RailGun {
+                angle: read_float(&mut rdr)?,
+                cooldown: read_float(&mut rdr)?,
+            },
+            5 => BlockInner::Armor,
+            6 => BlockInner::Rock,
+            _ => return Err(DecodeError::InvalidValue("block type")),
+        };
+        blocks.push((loc, Block { health, inner }));
+    }
+    check_end(&rdr)?;
+    // Blocks are already centered by the server, ignore center
+    let (mut blocky, _) = Blocky::new(blocks);
+    blocky.revision = Wrapping(revision);
+    Ok(blocky)
+}
+
+fn write_effect(writer: &mut Vec<u8>, effect: &EffectInner) {
+    match *effect {
+        EffectInner::Explosion(size) => {
+            writer.write_u8(1).unwrap();
+            write_float(writer, size);
+        }
+        EffectInner::MetalHit => writer.write_u8(2).unwrap(),
+        EffectInner::LaserHit => writer.write_u8(3).unwrap(),
+    }
+}
+
+fn read_effect<R: Read>(reader: &mut R) -> Result<EffectInner, DecodeError> {
+    match reader.read_u8()? {
+        1 => {
+            let size = read_float(reader)?;
+            if size > 0.0 && size <= MAX_EXPLOSION_SIZE {
+                Ok(EffectInner::Explosion(size))
+            } else {
+                Err(DecodeError::InvalidValue("explosion size"))
+            }
+        }
+        2 => Ok(EffectInner::MetalHit),
+        3 => Ok(EffectInner::LaserHit),
+        _ => Err(DecodeError::InvalidValue("effect type")),
+    }
+}
+
+/// The message exchanged by server and clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Message sent by a client to introduce itself, with the latest protocol
+    /// version it speaks, the room it wants to join, whether it only wants
+    /// to watch, without a ship, and the name it wants to be shown with.
+    ///
+    /// The server will reply with ServerHello, or Disconnect.
+    ClientHello(u16, u32, bool, String),
+    /// Message sent by a client that lost its connection, with the latest
+    /// protocol version it speaks. Its packets carry the ID and session
+    /// token it had.
+    ///
+    /// The server will reply with ServerHello and give it back its entities,
+    /// or Disconnect.
+    Reconnect(u16),
+    /// Message sent by a client that is leaving.
+    ///
+    /// The server will drop it and delete the entities it controls.
+    ClientBye,
+    /// Message sent by the server to accept a client, and assign it a client
+    /// ID and secret session token. Also has the protocol version to use.
+    ServerHello(u64, u64, u16),
+    /// Message sent by the server to close the connection.
+    Disconnect(DisconnectReason),
+    /// Ping request, other side should send bytes back as Pong.
+    Ping(u32),
+    /// Pong reply, with the bytes from the Ping request.
+    Pong(u32),
+    /// Duration of a server tick in seconds, from server.
+    ///
+    /// Sent after ServerHello, then whenever it changes.
+    TickLength(f32),
+    /// Half the width of the area ships are kept in, from server.
+    ///
+    /// Sent after ServerHello, then whenever it changes.
+    WorldSize(f32),
+    /// Message sent by the server to give the client an entity to
+    /// control.
+    StartEntityControl(u64),
+    /// New entity, from server, with the tick, its kind and initial state
+    /// (`QuantizedState`, without a baseline).
+    ///
+    /// Sent the first time a client sees an entity, then again from time to
+    /// time in case it got lost.
+    EntitySpawn(u64, u32, EntityKind, Vec<u8>),
+    /// Entity update, from either side.
+    ///
+    /// The server sends the tick and the state of entities that the client
+    /// applies (`QuantizedState`), components left out get removed. The client
+    /// sends update to the controls (`Controls`), preceded by its secret,
+    /// with the sequence number of that input.
+    EntityUpdate(u64, u32, Vec<u8>),
+    /// Entity deleted, from server.
+    EntityDelete(u64),
+    /// Entity states received by the client, as entity and tick.
+    ///
+    /// The server uses them as baselines for the next updates.
+    EntityAck(Vec<(u64, u32)>),
+    /// Structure of a `Blocky` entity, from server.
+    ///
+    /// Sent along with the first update, then whenever the revision changes.
+    EntityBlocky(u64, Vec<u8>),
+    /// Particle effect at a location, from server.
+    ///
+    /// Those are not entities on the client, just fire-and-forget events.
+    Effect(EffectInner, [f32; 2]),
+    /// Message that has to arrive, with its sequence number on the reliable
+    /// channel.
+    Reliable(u16, Vec<u8>),
+    /// Acknowledges the messages on the reliable channel, up to the given
+    /// sequence number (excluded).
+    ReliableAck(u16),
+    /// Message broadcast by clients looking for servers on the local
+    /// network.
+    ///
+    /// Servers reply with DiscoveryResponse.
+    DiscoveryRequest,
+    /// Description of the server, in reply to DiscoveryRequest.
+    DiscoveryResponse(ServerInfo),
+    /// Chat message, from client, on the reliable channel.
+    ChatSend(String),
+    /// Chat message, from server, with the name of the sender and the text,
+    /// on the reliable channel.
+    Chat(String, String),
+}
+
+impl Message {
+    /// Parse a message from some bytes.
+    pub fn parse(msg: &[u8]) -> Result<Message, DecodeError> {
+        if msg.len() < MAGIC.len() || &msg[..MAGIC.len()] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if msg.len() < 8 {
+            return Err(DecodeError::Truncated);
+        }
+        let mut rdr = Cursor::new(&msg[8..]);
+        let rest = |rdr: &mut Cursor<&[u8]>| -> Result<Vec<u8>, DecodeError> {
+            let mut v = Vec::new();
+            rdr.read_to_end(&mut v)?;
+            Ok(v)
+        };
+        let message = match &msg[6..8] {
+            b"hc" => Message::ClientHello(
+                rdr.read_u16::<ORDER>()?,
+                rdr.read_u32::<ORDER>()?,
+                rdr.read_u8()? != 0,
+                read_string(&mut rdr, MAX_NAME_LENGTH)?,
+            ),
+            b"rc" => Message::Reconnect(rdr.read_u16::<ORDER>()?),
+            b"by" => Message::ClientBye,
+            b"hs" => Message::ServerHello(
+                rdr.read_u64::<ORDER>()?,
+                rdr.read_u64::<ORDER>()?,
+                rdr.read_u16::<ORDER>()?,
+            ),
+            b"dc" => {
+                let code = rdr.read_u8()?;
+                Message::Disconnect(DisconnectReason::from_code(code)?)
+            }
+            b"pi" => Message::Ping(rdr.read_u32::<ORDER>()?),
+            b"po" => Message::Pong(rdr.read_u32::<ORDER>()?),
+            b"ec" => Message::StartEntityControl(rdr.read_u64::<ORDER>()?),
+            b"tl" => {
+                let length = read_float(&mut rdr)?;
+                if length <= 0.0 {
+                    return Err(DecodeError::InvalidValue("tick length"));
+                }
+                Message::TickLength(length)
+            }
+            b"ws" => {
+                let size = read_float(&mut rdr)?;
+                if size <= 0.0 {
+                    return Err(DecodeError::InvalidValue("world size"));
+                }
+                Message::WorldSize(size)
+            }
+            b"es" => {
+                let id = rdr.read_u64::<ORDER>()?;
+                let tick = rdr.read_u32::<ORDER>()?;
+                let kind = EntityKind::from_code(rdr.read_u8()?)?;
+                Message::EntitySpawn(id, tick, kind, rest(&mut rdr)?)
+            }
+            b"eu" => {
+                let id = rdr.read_u64::<ORDER>()?;
+                let tick = rdr.read_u32::<ORDER>()?;
+                Message::EntityUpdate(id, tick, rest(&mut rdr)?)
+            }
+            b"er" => Message::EntityDelete(rdr.read_u64::<ORDER>()?),
+            b"ea" => {
+                let len = rdr.read_u16::<ORDER>()?;
+                let mut acks = Vec::new();
+                for _ in 0..len {
+                    acks.push((
+                        rdr.read_u64::<ORDER>()?,
+                        rdr.read_u32::<ORDER>()?,
+                    ));
+                }
+                Message::EntityAck(acks)
+            }
+            b"eb" => {
+                let id = rdr.read_u64::<ORDER>()?;
+                Message::EntityBlocky(id, rest(&mut rdr)?)
+            }
+            b"fx" => {
+                let effect = read_effect(&mut rdr)?;
+                let pos = [read_float(&mut rdr)?, read_float(&mut rdr)?];
+                Message::Effect(effect, pos)
+            }
+            b"rl" => {
+                let seq = rdr.read_u16::<ORDER>()?;
+                Message::Reliable(seq, rest(&mut rdr)?)
+            }
+            b"ra" => Message::ReliableAck(rdr.read_u16::<ORDER>()?),
+            b"dq" => Message::DiscoveryRequest,
+            b"di" => Message::DiscoveryResponse(ServerInfo::read(&mut rdr)?),
+            b"cs" => {
+                let text = read_string(&mut rdr, MAX_CHAT_LENGTH)?;
+                Message::ChatSend(text)
+            }
+            b"cm" => Message::Chat(
+                read_string(&mut rdr, MAX_NAME_LENGTH)?,
+                read_string(&mut rdr, MAX_CHAT_LENGTH)?,
+            ),
+            t => return Err(DecodeError::UnknownMessage([t[0], t[1]])),
+        };
+        check_end(&rdr)?;
+        Ok(message)
+    }
+
+    /// Write a message into a vector of bytes.
+    pub fn to_bytes(&self, msg: &mut Vec<u8>) {
+        msg.extend_from_slice(MAGIC);
+        match *self {
+            Message::ClientHello(version, room, spectate, ref name) => {
+                msg.extend_from_slice(b"hc");
+                msg.write_u16::<ORDER>(version).unwrap();
+                msg.write_u32::<ORDER>(room).unwrap();
+                msg.write_u8(spectate as u8).unwrap();
+                write_string(msg, name, MAX_NAME_LENGTH);
+            }
+            Message::Reconnect(version) => {
+                msg.extend_from_slice(b"rc");
+                msg.write_u16::<ORDER>(version).unwrap();
+            }
+            Message::ClientBye => msg.extend_from_slice(b"by"),
+            Message::ServerHello(id, token, version) => {
+                msg.extend_from_slice(b"hs");
+                msg.write_u64::<ORDER>(id).unwrap();
+                msg.write_u64::<ORDER>(token).unwrap();
+                msg.write_u16::<ORDER>(version).unwrap();
+            }
+            Message::Disconnect(reason) => {
+                msg.extend_from_slice(b"dc");
+                msg.write_u8(reason.code()).unwrap();
+            }
+            Message::Ping(buf) => {
+                msg.extend_from_slice(b"pi");
+                msg.write_u32::<ORDER>(buf).unwrap();
+            }
+            Message::Pong(buf) => {
+                msg.extend_from_slice(b"po");
+                msg.write_u32::<ORDER>(buf).unwrap();
+            }
+            Message::StartEntityControl(id) => {
+                msg.extend_from_slice(b"ec");
+                msg.write_u64::<ORDER>(id).unwrap();
+            }
+            Message::TickLength(length) => {
+                msg.extend_from_slice(b"tl");
+                write_float(msg, length);
+            }
+            Message::WorldSize(size) => {
+                msg.extend_from_slice(b"ws");
+                write_float(msg, size);
+            }
+            Message::EntitySpawn(id, tick, kind, ref bytes) => {
+                msg.extend_from_slice(b"es");
+                msg.write_u64::<ORDER>(id).unwrap();
+                msg.write_u32::<ORDER>(tick).unwrap();
+                msg.write_u8(kind.code()).unwrap();
+                msg.extend_from_slice(bytes);
+            }
+            Message::EntityUpdate(id, tick, ref bytes) => {
+                msg.extend_from_slice(b"eu");
+                msg.write_u64::<ORDER>(id).unwrap();
+                msg.write_u32::<ORDER>(tick).unwrap();
+                msg.extend_from_slice(bytes);
+            }
+            Message::EntityDelete(id) => {
+                msg.extend_from_slice(b"er");
+                msg.write_u64::<ORDER>(id).unwrap();
+            }
+            Message::EntityAck(ref acks) => {
+                msg.extend_from_slice(b"ea");
+                msg.write_u16::<ORDER>(acks.len() as u16).unwrap();
+                for &(id, tick) in acks {
+                    msg.write_u64::<ORDER>(id).unwrap();
+                    msg.write_u32::<ORDER>(tick).unwrap();
+                }
+            }
+            Message::EntityBlocky(id, ref bytes) => {
+                msg.extend_from_slice(b"eb");
+                msg.write_u64::<ORDER>(id).unwrap();
+                msg.extend_from_slice(bytes);
+            }
+            Message::Effect(ref effect, pos) => {
+                msg.extend_from_slice(b"fx");
+                write_effect(msg, effect);
+                write_float(msg, pos[0]);
+                write_float(msg, pos[1]);
+            }
+            Message::Reliable(seq, ref bytes) => {
+                msg.extend_from_slice(b"rl");
+                msg.write_u16::<ORDER>(seq).unwrap();
+                msg.extend_from_slice(bytes);
+            }
+            Message::ReliableAck(seq) => {
+                msg.extend_from_slice(b"ra");
+                msg.write_u16::<ORDER>(seq).unwrap();
+            }
+            Message::DiscoveryRequest => msg.extend_from_slice(b"dq"),
+            Message::DiscoveryResponse(ref info) => {
+                msg.extend_from_slice(b"di");
+                info.write(msg);
+            }
+            Message::ChatSend(ref text) => {
+                msg.extend_from_slice(b"cs");
+                write_string(msg, text, MAX_CHAT_LENGTH);
+            }
+            Message::Chat(ref sender, ref text) => {
+                msg.extend_from_slice(b"cm");
+                write_string(msg, sender, MAX_NAME_LENGTH);
+                write_string(msg, text, MAX_CHAT_LENGTH);
+            }
+        }
+    }
+
+    /// Turn a message into bytes.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut msg: Vec<u8> = Vec::with_capacity(20);
+        self.to_bytes(&mut msg);
+        msg
+    }
+}
+
+/// Gets the type of an encoded message, without decoding it. Messages on the
+/// reliable channel count as what they carry.
+pub fn message_type(msg: &[u8]) -> &'static str {
+    if msg.len() < 8 || &msg[..MAGIC.len()] != MAGIC {
+        return "Invalid";
+    }
+    match &msg[6..8] {
+        b"hc" => "ClientHello",
+        b"rc" => "Reconnect",
+        b"by" => "ClientBye",
+        b"hs" => "ServerHello",
+        b"dc" => "Disconnect",
+        b"pi" => "Ping",
+        b"po" => "Pong",
+        b"ec" => "StartEntityControl",
+        b"tl" => "TickLength",
+        b"ws" => "WorldSize",
+        b"es" => "EntitySpawn",
+        b"eu" => "EntityUpdate",
+        b"er" => "EntityDelete",
+        b"ea" => "EntityAck",
+        b"eb" => "EntityBlocky",
+        b"fx" => "Effect",
+        b"rl" if msg.len() > 10 => message_type(&msg[10..]),
+        b"ra" => "ReliableAck",
+        b"dq" => "DiscoveryRequest",
+        b"di" => "DiscoveryResponse",
+        b"cs" => "ChatSend",
+        b"cm" => "Chat",
+        _ => "Invalid",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, Rng};
+    use std::mem::discriminant;
+
+    use super::{message_type, read_blocky, read_string, write_blocky,
+                write_string, Controls, DecodeError, DisconnectReason,
+                EntityKind, EntityState, Message, QuantizedState,
+                ServerInfo, COMPONENT_PROJECTILE, COMPONENT_VELOCITY};
+    use crate::blocks::{Block, BlockInner, Blocky};
+    use crate::guns::ProjectileType;
+    use crate::particles::EffectInner;
+    use crate::physics::{Position, Velocity};
+    use crate::ship::Ship;
+
+    fn random_bytes<R: Rng>(rng: &mut R, max: usize) -> Vec<u8> {
+        let len = rng.gen_range(0, max);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn random_message<R: Rng>(rng: &mut R) -> Message {
+        match rng.gen_range(0, 22) {
+            0 => Message::ClientHello(
+                rng.gen(),
+                rng.gen(),
+                rng.gen(),
+                "Pilot é".to_owned(),
+            ),
+            1 => Message::ClientBye,
+            2 => Message::ServerHello(rng.gen(), rng.gen(), rng.gen()),
+            3 => Message::Disconnect(DisconnectReason::VersionMismatch),
+            4 => Message::Ping(rng.gen()),
+            5 => Message::Pong(rng.gen()),
+            6 => Message::StartEntityControl(rng.gen()),
+            7 => Message::EntityUpdate(
+                rng.gen(),
+                rng.gen(),
+                random_bytes(rng, 64),
+            ),
+            8 => Message::EntityDelete(rng.gen()),
+            9 => Message::EntityBlocky(rng.gen(), random_bytes(rng, 64)),
+            10 => Message::EntitySpawn(
+                rng.gen(),
+                rng.gen(),
+                match rng.gen_range(0, 4) {
+                    0 => EntityKind::Ship,
+                    1 => EntityKind::Asteroid,
+                    2 => EntityKind::Debris,
+                    _ => EntityKind::Projectile,
+                },
+                random_bytes(rng, 64),
+            ),
+            11 => Message::TickLength(rng.gen_range(0.001, 1.0)),
+            12 => Message::EntityAck(
+                (0..rng.gen_range(0, 8))
+                    .map(|_| (rng.gen(), rng.gen()))
+                    .collect(),
+            ),
+            13 => Message::Reliable(rng.gen(), random_bytes(rng, 64)),
+            14 => Message::ReliableAck(rng.gen()),
+            15 => Message::Reconnect(rng.gen()),
+            16 => Message::DiscoveryRequest,
+            17 => Message::DiscoveryResponse(ServerInfo {
+                name: "Server é".to_owned(),
+                players: rng.gen(),
+                max_players: rng.gen(),
+                map: String::new(),
+                protocol_version: rng.gen(),
+                min_protocol_version: rng.gen(),
+            }),
+            18 => Message::WorldSize(rng.gen_range(1.0, 1000.0)),
+            19 => Message::ChatSend("gg ☺".to_owned()),
+            20 => Message::Chat("Pilot é".to_owned(), String::new()),
+            _ => Message::Effect(
+                match rng.gen_range(0, 3) {
+                    0 => EffectInner::Explosion(rng.gen_range(0.1, 16.0)),
+                    1 => EffectInner::MetalHit,
+                    _ => EffectInner::LaserHit,
+                },
+                [rng.gen_range(-200.0, 200.0), rng.gen_range(-200.0, 200.0)],
+            ),
+        }
+    }
+
+    fn test_blocky() -> Blocky {
+        let (blocky, _) = Blocky::new(vec![
+            ([0.0, 0.0], Block::new(BlockInner::Cockpit)),
+            ([1.0, 0.0], Block::new(BlockInner::Thruster { angle: 1.5 })),
+            (
+                [0.0, 1.0],
+                Block::new(BlockInner::PlasmaGun {
+                    angle: 0.2,
+                    cooldown: -1.0,
+                }),
+            ),
+            (
+                [0.0, -1.0],
+                Block::new(BlockInner::RailGun {
+                    angle: 0.0,
+                    cooldown: 0.5,
+                }),
+            ),
+            ([-1.0, 0.0], Block::new(BlockInner::Armor)),
+            ([-1.0, 1.0], Block::new(BlockInner::Rock)),
+        ]);
+        blocky
+    }
+
+    #[test]
+    fn test_effect_roundtrip() {
+        for effect in &[
+            EffectInner::Explosion(0.4),
+            EffectInner::MetalHit,
+            EffectInner::LaserHit,
+        ] {
+            let bytes = Message::Effect(effect.clone(), [12.5, -3.0]).bytes();
+            match Message::parse(&bytes) {
+                Ok(Message::Effect(e, pos)) => {
+                    assert_eq!(&e, effect);
+                    assert_eq!(pos, [12.5, -3.0]);
+                }
+                _ => panic!("Effect didn't parse back"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..2000 {
+            let msg = random_message(&mut rng);
+            assert_eq!(Message::parse(&msg.bytes()), Ok(msg));
+        }
+
+        assert_eq!(message_type(&Message::Ping(3).bytes()), "Ping");
+        let reliable = Message::Reliable(1, Message::EntityDelete(4).bytes());
+        assert_eq!(message_type(&reliable.bytes()), "EntityDelete");
+        assert_eq!(message_type(b"SPAC"), "Invalid");
+    }
+
+    #[test]
+    fn test_truncated_messages() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let msg = random_message(&mut rng);
+            let bytes = msg.bytes();
+            for len in 0..bytes.len() {
+                if let Ok(m) = Message::parse(&bytes[..len]) {
+                    // Only messages with a variable-length tail can be cut
+                    // and still parse as the same kind of message
+                    assert_eq!(discriminant(&msg), discriminant(&m));
+                }
+            }
+            let mut long = bytes.clone();
+            long.push(0);
+            match msg {
+                Message::EntitySpawn(..)
+                | Message::EntityUpdate(..)
+                | Message::EntityBlocky(..)
+                | Message::Reliable(..) => {}
+                _ => assert_eq!(
+                    Message::parse(&long),
+                    Err(DecodeError::TrailingBytes)
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_bytes() {
+        let mut rng = rand::thread_rng();
+        let types: &[&[u8]] = &[
+            b"hc", b"rc", b"by", b"hs", b"dc", b"pi", b"po", b"tl", b"ws",
+            b"ec", b"es", b"eu", b"er", b"ea", b"eb", b"fx", b"rl", b"ra",
+            b"dq", b"di", b"cs", b"cm", b"zz",
+        ];
+        for _ in 0..20000 {
+            // Completely random
+            let _ = Message::parse(&random_bytes(&mut rng, 40));
+            // Valid header, random content
+            let mut bytes = b"SPAC\x00\x01".to_vec();
+            bytes.extend_from_slice(types[rng.gen_range(0, types.len())]);
+            bytes.extend_from_slice(&random_bytes(&mut rng, 40));
+            let _ = Message::parse(&bytes);
+        }
+    }
+
+    #[test]
+    fn test_mutated_messages() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20000 {
+            let mut bytes = random_message(&mut rng).bytes();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(0, bytes.len());
+                bytes[i] = rng.gen();
+            }
+            let _ = Message::parse(&bytes);
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut data = Vec::new();
+        write_string(&mut data, "héllo", 64);
+        write_string(&mut data, "héllo", 2);
+        let mut rdr = &data[..];
+        assert_eq!(read_string(&mut rdr, 64).unwrap(), "héllo");
+        // Cut on a character boundary
+        assert_eq!(read_string(&mut rdr, 64).unwrap(), "h");
+        assert!(rdr.is_empty());
+
+        let mut rdr = &data[..];
+        assert!(read_string(&mut rdr, 4).is_err());
+        assert!(read_string(&mut &[0, 2, 0xC3, 0x28][..], 64).is_err());
+    }
+
+    #[test]
+    fn test_payloads() {
+        let pos = Position {
+            pos: [1.0, 2.0],
+            rot: 0.5,
+        };
+        let vel = Velocity {
+            vel: [-1.0, 0.0],
+            rot: 0.1,
+        };
+
+        let mut ship = Ship::new();
+        ship.want_thrust = [1.0, 0.0];
+        ship.thrust_rot = 4.0;
+        let state = EntityState {
+            position: Some(pos.clone()),
+            velocity: Some(vel.clone()),
+            ship: Some(ship),
+            projectile: None,
+            input_ack: Some(12),
+        };
+        let mut data = Vec::new();
+        state.quantize().write(&mut data, None);
+        let parsed = QuantizedState::read(&data, None).unwrap().state();
+        assert_eq!(parsed.components(), state.components());
+        assert_eq!(
+            parsed.components() & EntityKind::Ship.required_components(),
+            EntityKind::Ship.required_components()
+        );
+        let (p, v) = (parsed.position.unwrap(), parsed.velocity.unwrap());
+        assert_eq!(p.pos, [1.0, 2.0]);
+        assert!((p.rot - 0.5).abs() < 1e-3);
+        assert_eq!(v.vel, [-1.0, 0.0]);
+        assert!((v.rot - 0.1).abs() < 1e-3);
+        let s = parsed.ship.unwrap();
+        assert_eq!(s.want_thrust, [1.0, 0.0]);
+        assert_eq!(s.thrust_rot, 4.0);
+        assert_eq!(parsed.input_ack, Some(12));
+
+        // Components can be left out
+        let state = EntityState {
+            velocity: Some(vel.clone()),
+            projectile: Some((ProjectileType::Rail, 5 << 32 | 42)),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        state.quantize().write(&mut data, None);
+        let parsed = QuantizedState::read(&data, None).unwrap().state();
+        assert_eq!(
+            parsed.components(),
+            COMPONENT_VELOCITY | COMPONENT_PROJECTILE
+        );
+        assert_eq!(
+            parsed.projectile,
+            Some((ProjectileType::Rail, 5 << 32 | 42))
+        );
+
+        // Components we don't know about are rejected
+        assert_eq!(
+            QuantizedState::read(&[0x00, 0x80, 0x00], None).err(),
+            Some(DecodeError::InvalidValue("component mask"))
+        );
+
+        let controls = Controls {
+            fire: true,
+            thrust: [-1.0, 1.0],
+            thrust_rot: 1.0,
+            target: [3.0, -4.0],
+            view_tick: 42,
+        };
+        let mut data = Vec::new();
+        controls.write(&mut data);
+        assert_eq!(Controls::read(&data), Ok(controls.clone()));
+
+        // Aiming absurdly far is rejected
+        let controls = Controls {
+            target: [1.0e6, 0.0],
+            ..controls
+        };
+        let mut data = Vec::new();
+        controls.write(&mut data);
+        assert_eq!(
+            Controls::read(&data),
+            Err(DecodeError::InvalidValue("control target"))
+        );
+
+        let blocky = test_blocky();
+        let mut data = Vec::new();
+        write_blocky(&mut data, &blocky);
+        let parsed = read_blocky(&data).unwrap();
+        assert_eq!(parsed.blocks.len(), blocky.blocks.len());
+        assert_eq!(parsed.revision, blocky.revision);
+
+        // Non-finite floats are rejected, or can't be sent
+        let msg = Message::TickLength(f32::NAN).bytes();
+        assert!(Message::parse(&msg).is_err());
+        let state = EntityState {
+            position: Some(pos),
+            velocity: Some(Velocity {
+                vel: [f32::NAN, f32::INFINITY],
+                rot: 0.0,
+            }),
+            ..Default::default()
+        };
+        let vel = state.quantize().state().velocity.unwrap();
+        assert!(vel.vel[0].is_finite() && vel.vel[1].is_finite());
+    }
+
+    #[test]
+    fn test_delta() {
+        let state = |x: f32, ack: u32| EntityState {
+            position: Some(Position {
+                pos: [x, -40.0],
+                rot: 3.0,
+            }),
+            velocity: Some(Velocity {
+                vel: [2.5, 0.0],
+                rot: -1.0,
+            }),
+            ship: Some(Ship::new()),
+            input_ack: Some(ack),
+            ..Default::default()
+        };
+        let base = state(100.0, u32::MAX).quantize();
+        let new = state(100.5, 3).quantize();
+
+        let mut full = Vec::new();
+        new.write(&mut full, None);
+        let mut delta = Vec::new();
+        new.write(&mut delta, Some((4, &base)));
+        // Unchanged fields take a byte each
+        assert!(delta.len() < full.len());
+        assert_eq!(delta.len(), 1 + 2 + 16);
+
+        assert_eq!(QuantizedState::baseline_age(&delta), Ok(4));
+        assert_eq!(QuantizedState::read(&delta, Some(&base)), Ok(new.clone()));
+        assert_eq!(
+            QuantizedState::read(&delta, None),
+            Err(DecodeError::MissingBaseline)
+        );
+        assert_eq!(QuantizedState::read(&full, Some(&base)), Ok(new.clone()));
+
+        // Components missing from the baseline are sent in full
+        let base = EntityState::default().quantize();
+        let mut data = Vec::new();
+        new.write(&mut data, Some((1, &base)));
+        assert_eq!(QuantizedState::read(&data, Some(&base)), Ok(new));
+    }
+
+    #[test]
+    fn test_random_payloads() {
+        let mut rng = rand::thread_rng();
+        let mut blocky = Vec::new();
+        write_blocky(&mut blocky, &test_blocky());
+        for _ in 0..20000 {
+            let _ = QuantizedState::read(&random_bytes(&mut rng, 60), None);
+            let _ = Controls::read(&random_bytes(&mut rng, 16));
+            let _ = read_blocky(&random_bytes(&mut rng, 60));
+
+            let mut data = blocky.clone();
+            let i = rng.gen_range(0, data.len());
+            data[i] = rng.gen();
+            let len = rng.gen_range(0, data.len() + 1);
+            let _ = read_blocky(&data[..len]);
+        }
+    }
+}
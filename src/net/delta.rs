@@ -0,0 +1,188 @@
+//! Delta compression of entity states.
+//!
+//! The server writes each state as the difference from the latest state of
+//! that entity the client acknowledged. Both sides keep the recent states
+//! they sent or received, so they agree on what the baseline is.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::codec::{DecodeError, EntityState, QuantizedState};
+
+/// Oldest baseline used, in ticks. Older than this, states are sent in full.
+const MAX_BASELINE_AGE: u32 = 64;
+
+/// States sent to a client for an entity, waiting to be acknowledged.
+struct SentStates {
+    sent: VecDeque<(u32, QuantizedState)>,
+    acked: Option<(u32, QuantizedState)>,
+}
+
+/// Server-side baselines of a client.
+pub struct Baselines {
+    entities: HashMap<u64, SentStates>,
+}
+
+impl Baselines {
+    pub fn new() -> Baselines {
+        Baselines {
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Writes the state of an entity for this client, and remembers it.
+    ///
+    /// The state is written against the latest one acknowledged, unless
+    /// `full` is set.
+    pub fn encode(
+        &mut self,
+        id: u64,
+        tick: u32,
+        state: QuantizedState,
+        full: bool,
+    ) -> Vec<u8> {
+        let entry = self.entities.entry(id).or_insert_with(|| SentStates {
+            sent: VecDeque::new(),
+            acked: None,
+        });
+        let baseline = match entry.acked {
+            Some((acked, ref base)) if !full => {
+                let age = tick.wrapping_sub(acked);
+                if age > 0 && age <= MAX_BASELINE_AGE {
+                    Some((age, base))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let mut data = Vec::new();
+        state.write(&mut data, baseline);
+
+        entry
+            .sent
+            .retain(|&(t, _)| tick.wrapping_sub(t) < MAX_BASELINE_AGE);
+        entry.sent.push_back((tick, state));
+        data
+    }
+
+    /// Records that the client received the state of an entity at a tick.
+    pub fn acknowledge(&mut self, id: u64, tick: u32) {
+        let entry = match self.entities.get_mut(&id) {
+            Some(e) => e,
+            None => return,
+        };
+        let idx = match entry.sent.iter().position(|&(t, _)| t == tick) {
+            Some(i) => i,
+            None => return,
+        };
+        // Older states won't be needed anymore
+        entry.acked = entry.sent.drain(..=idx).next_back();
+    }
+
+    /// Whether the client acknowledged any state of an entity.
+    pub fn acknowledged(&self, id: u64) -> bool {
+        self.entities.get(&id).is_some_and(|e| e.acked.is_some())
+    }
+
+    /// Forgets about an entity the client no longer knows.
+    pub fn forget(&mut self, id: u64) {
+        self.entities.remove(&id);
+    }
+}
+
+/// Client-side states received, that the server can use as baselines.
+pub struct History {
+    entities: HashMap<u64, VecDeque<(u32, QuantizedState)>>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Reads the state of an entity at a tick, and remembers it.
+    pub fn decode(
+        &mut self,
+        id: u64,
+        tick: u32,
+        data: &[u8],
+    ) -> Result<EntityState, DecodeError> {
+        let states = self.entities.entry(id).or_default();
+        let baseline = match QuantizedState::baseline_age(data)? {
+            0 => None,
+            age => {
+                let base_tick = tick.wrapping_sub(age);
+                states.iter().find(|&&(t, _)| t == base_tick).map(|(_, s)| s)
+            }
+        };
+        let state = QuantizedState::read(data, baseline)?;
+        let result = state.state();
+
+        // Keep states the server might still use, as they come in order or
+        // not
+        states.push_back((tick, state));
+        let newest = states
+            .iter()
+            .map(|&(t, _)| t)
+            .max_by_key(|&t| t.wrapping_sub(tick) as i32)
+            .unwrap();
+        states.retain(|&(t, _)| newest.wrapping_sub(t) <= MAX_BASELINE_AGE);
+        Ok(result)
+    }
+
+    /// Forgets about an entity that is gone.
+    pub fn forget(&mut self, id: u64) {
+        self.entities.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Baselines, History};
+    use crate::net::codec::{DecodeError, EntityState};
+    use crate::physics::Position;
+
+    fn state(x: f32) -> EntityState {
+        EntityState {
+            position: Some(Position {
+                pos: [x, 0.0],
+                rot: 0.0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_baselines() {
+        let mut baselines = Baselines::new();
+        let mut history = History::new();
+
+        // Nothing acknowledged yet, sent in full
+        let first = baselines.encode(1, 10, state(1.0).quantize(), false);
+        let lost = baselines.encode(1, 11, state(2.0).quantize(), false);
+        assert_eq!(first.len(), lost.len());
+        history.decode(1, 10, &first).unwrap();
+        baselines.acknowledge(1, 10);
+
+        // Against the acknowledged state
+        let data = baselines.encode(1, 12, state(1.125).quantize(), false);
+        assert!(data.len() < first.len());
+        let pos = history.decode(1, 12, &data).unwrap().position.unwrap();
+        assert_eq!(pos.pos[0], 1.125);
+
+        // Client doesn't have the lost state
+        let mut other = History::new();
+        assert_eq!(
+            other.decode(1, 12, &data).err(),
+            Some(DecodeError::MissingBaseline)
+        );
+
+        // Forced full, or baseline too old
+        let data = baselines.encode(1, 13, state(4.0).quantize(), true);
+        assert_eq!(data.len(), first.len());
+        let data = baselines.encode(1, 100, state(4.0).quantize(), false);
+        assert_eq!(data.len(), first.len());
+    }
+}
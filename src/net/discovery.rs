@@ -0,0 +1,120 @@
+//! Finding servers on the local network.
+//!
+//! Clients broadcast a `DiscoveryRequest` over UDP, to the port servers
+//! usually listen on, and each server that gets it replies with a
+//! `DiscoveryResponse` describing itself.
+
+use log::info;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use super::codec::Message;
+use super::packet::{Packer, Unpacker, MAX_PACKET_SIZE};
+pub use super::codec::ServerInfo;
+
+/// Port servers listen on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 34244;
+
+/// The packet asking servers to describe themselves.
+///
+/// Like every packet from a client, it starts with a client ID and token,
+/// here 0 as we are not connected.
+pub fn request_packet() -> Vec<u8> {
+    let mut packer = Packer::new(MAX_PACKET_SIZE - 16);
+    packer.push(&Message::DiscoveryRequest.bytes());
+    let mut packet = vec![0; 16];
+    packet.extend_from_slice(&packer.take()[0]);
+    packet
+}
+
+/// Reads the description of a server out of its reply, if that's what the
+/// packet is.
+pub fn read_response(packet: &[u8]) -> Option<ServerInfo> {
+    let messages = Unpacker::new().unpack(packet).ok()?;
+    messages.iter().find_map(|msg| match Message::parse(msg) {
+        Ok(Message::DiscoveryResponse(info)) => Some(info),
+        _ => None,
+    })
+}
+
+/// A server that answered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoundServer {
+    pub address: SocketAddr,
+    pub info: ServerInfo,
+}
+
+/// Looks for servers on the local network.
+///
+/// The request is sent when created and on `refresh()`, and the servers are
+/// collected as their replies come in with `poll()`.
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    target: SocketAddr,
+    servers: Vec<FoundServer>,
+}
+
+impl LanDiscovery {
+    /// Broadcasts a request to the given port.
+    pub fn new(port: u16) -> io::Result<LanDiscovery> {
+        let broadcast = IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255));
+        LanDiscovery::with_target(SocketAddr::new(broadcast, port))
+    }
+
+    /// Sends the request to a specific address, which may be a broadcast
+    /// address or a single server.
+    pub fn with_target(target: SocketAddr) -> io::Result<LanDiscovery> {
+        let unspec = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let socket = UdpSocket::bind(SocketAddr::new(unspec, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        let mut discovery = LanDiscovery {
+            socket,
+            target,
+            servers: Vec::new(),
+        };
+        discovery.refresh()?;
+        Ok(discovery)
+    }
+
+    /// Forgets the servers found so far, and asks again.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        self.servers.clear();
+        self.socket.send_to(&request_packet(), self.target)?;
+        Ok(())
+    }
+
+    /// Reads the replies that came in, and returns all the servers found.
+    pub fn poll(&mut self) -> &[FoundServer] {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        while let Ok((len, address)) = self.socket.recv_from(&mut buffer) {
+            let info = match read_response(&buffer[..len]) {
+                Some(i) => i,
+                None => {
+                    info!("Invalid discovery response from {}", address);
+                    continue;
+                }
+            };
+            let found = FoundServer { address, info };
+            match self.servers.iter_mut().find(|s| s.address == address) {
+                Some(server) => *server = found,
+                None => self.servers.push(found),
+            }
+        }
+        &self.servers
+    }
+}
+
+/// Broadcasts a request to the given port, and lists the servers that
+/// answer within `wait`.
+pub fn discover(port: u16, wait: Duration) -> io::Result<Vec<FoundServer>> {
+    let mut discovery = LanDiscovery::new(port)?;
+    let start = Instant::now();
+    while start.elapsed() < wait {
+        sleep(Duration::from_millis(10));
+        discovery.poll();
+    }
+    Ok(discovery.poll().to_vec())
+}
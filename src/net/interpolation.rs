@@ -0,0 +1,296 @@
+//! Snapshot interpolation, to smooth the motion of entities on clients.
+//!
+//! The server only sends updates at its own tick rate, and they arrive with
+//! jitter. Instead of applying them as they come in, clients keep a buffer of
+//! snapshots for each entity and show the world a little in the past,
+//! interpolating between the two snapshots around that time.
+
+use specs::{Component, VecStorage};
+use std::collections::VecDeque;
+use vecmath::*;
+
+use crate::physics::{Position, Velocity};
+use crate::utils::angle_wrap;
+
+/// Most snapshots kept per entity.
+const MAX_SNAPSHOTS: usize = 32;
+
+/// If the playback clock is off by more than that, in seconds, jump instead
+/// of catching up slowly.
+const MAX_CLOCK_DRIFT: f64 = 0.5;
+
+/// Most the playback clock speeds up or slows down to catch up.
+const MAX_CLOCK_ADJUST: f64 = 0.1;
+
+/// How many ticks past the latest snapshot entities keep moving, before
+/// they stop and wait for the next one.
+const MAX_EXTRAPOLATION_TICKS: f64 = 4.0;
+
+/// State of an entity at some point in server time.
+struct Snapshot {
+    time: f64,
+    pos: Position,
+    vel: Velocity,
+}
+
+/// Client component buffering the states received for an entity.
+pub struct Snapshots {
+    buffer: VecDeque<Snapshot>,
+}
+
+impl Component for Snapshots {
+    type Storage = VecStorage<Self>;
+}
+
+impl Default for Snapshots {
+    fn default() -> Snapshots {
+        Snapshots::new()
+    }
+}
+
+impl Snapshots {
+    pub fn new() -> Snapshots {
+        Snapshots {
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Adds the state of the entity at the given server time.
+    ///
+    /// Snapshots can be added out of order.
+    pub fn insert(&mut self, time: f64, pos: Position, vel: Velocity) {
+        let idx = self.buffer
+            .iter()
+            .position(|s| s.time >= time)
+            .unwrap_or(self.buffer.len());
+        let snapshot = Snapshot { time, pos, vel };
+        match self.buffer.get_mut(idx) {
+            Some(s) if s.time == time => *s = snapshot,
+            _ => self.buffer.insert(idx, snapshot),
+        }
+        if self.buffer.len() > MAX_SNAPSHOTS {
+            self.buffer.pop_front();
+        }
+    }
+
+    /// Computes the state of the entity at the given server time.
+    ///
+    /// This interpolates between the snapshots around that time, or
+    /// extrapolates from the latest one using its velocity if there is none
+    /// after, which covers updates the server sends late or not at all.
+    /// Extrapolation only goes `max_extrapolation` seconds ahead, after which
+    /// the entity holds still, so one that the server stopped talking about
+    /// doesn't fly away. Snapshots older than needed are dropped.
+    pub fn sample(
+        &mut self,
+        time: f64,
+        max_extrapolation: f64,
+    ) -> Option<(Position, Velocity)> {
+        // Drop snapshots we won't need anymore
+        while self.buffer.len() >= 2 && self.buffer[1].time <= time {
+            self.buffer.pop_front();
+        }
+
+        let a = self.buffer.front()?;
+        let b = match self.buffer.get(1) {
+            Some(b) if a.time <= time => b,
+            // Before the first snapshot, or after the last one
+            _ => {
+                let dt = time - a.time;
+                let vel = if dt < max_extrapolation {
+                    a.vel.clone()
+                } else {
+                    Velocity {
+                        vel: [0.0, 0.0],
+                        rot: 0.0,
+                    }
+                };
+                let dt = dt.clamp(0.0, max_extrapolation) as f32;
+                return Some((
+                    Position {
+                        pos: vec2_add(a.pos.pos, vec2_scale(a.vel.vel, dt)),
+                        rot: a.pos.rot + a.vel.rot * dt,
+                    },
+                    vel,
+                ));
+            }
+        };
+
+        let t = ((time - a.time) / (b.time - a.time)) as f32;
+        let lerp = |x: f32, y: f32| x + (y - x) * t;
+        Some((
+            Position {
+                pos: [
+                    lerp(a.pos.pos[0], b.pos.pos[0]),
+                    lerp(a.pos.pos[1], b.pos.pos[1]),
+                ],
+                rot: a.pos.rot + angle_wrap(b.pos.rot - a.pos.rot) * t,
+            },
+            Velocity {
+                vel: [
+                    lerp(a.vel.vel[0], b.vel.vel[0]),
+                    lerp(a.vel.vel[1], b.vel.vel[1]),
+                ],
+                rot: lerp(a.vel.rot, b.vel.rot),
+            },
+        ))
+    }
+}
+
+/// Clock following the server's ticks, a set delay behind.
+pub struct PlaybackClock {
+    /// Duration of a server tick, in seconds.
+    pub tick_length: f32,
+    /// Latest tick received, and its time.
+    latest: Option<(u32, f64)>,
+    /// Server time currently being shown.
+    playback: Option<f64>,
+}
+
+impl PlaybackClock {
+    pub fn new() -> PlaybackClock {
+        PlaybackClock {
+            tick_length: 0.0,
+            latest: None,
+            playback: None,
+        }
+    }
+
+    /// Gets the server time of a tick, and records it if it is the latest.
+    ///
+    /// Ticks wrap around, and are turned into a time relative to the latest
+    /// one, so changes to the tick length only affect new ticks.
+    pub fn tick_time(&mut self, tick: u32) -> f64 {
+        let (latest_tick, latest_time) = match self.latest {
+            Some(l) => l,
+            None => {
+                self.latest = Some((tick, 0.0));
+                return 0.0;
+            }
+        };
+        let ticks = tick.wrapping_sub(latest_tick) as i32;
+        let time = latest_time + ticks as f64 * self.tick_length as f64;
+        if ticks > 0 {
+            self.latest = Some((tick, time));
+        }
+        time
+    }
+
+    /// Moves the clock forward, returning the server time to show.
+    ///
+    /// The clock runs slightly faster or slower to stay `delay` seconds
+    /// behind the latest tick received.
+    pub fn advance(&mut self, dt: f32, delay: f32) -> Option<f64> {
+        let target = self.latest?.1 - delay as f64;
+        let playback = match self.playback {
+            Some(p) if (target - p).abs() <= MAX_CLOCK_DRIFT => {
+                let adjust = ((target - p) * 2.0)
+                    .clamp(-MAX_CLOCK_ADJUST, MAX_CLOCK_ADJUST);
+                p + dt as f64 * (1.0 + adjust)
+            }
+            _ => target,
+        };
+        self.playback = Some(playback);
+        Some(playback)
+    }
+
+    /// How far ahead of the latest snapshot entities should be extrapolated,
+    /// in seconds.
+    pub fn max_extrapolation(&self) -> f64 {
+        MAX_EXTRAPOLATION_TICKS * self.tick_length as f64
+    }
+
+    /// Gets the tick being shown, as of the last time the clock advanced.
+    pub fn playback_tick(&self) -> Option<u32> {
+        let (tick, time) = self.latest?;
+        let playback = self.playback?;
+        if self.tick_length <= 0.0 {
+            return Some(tick);
+        }
+        let behind = ((time - playback) / self.tick_length as f64).round();
+        Some(tick.wrapping_sub(behind.max(0.0) as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PlaybackClock, Snapshots};
+    use crate::physics::{Position, Velocity};
+
+    fn state(x: f32, vx: f32) -> (Position, Velocity) {
+        (
+            Position {
+                pos: [x, 0.0],
+                rot: 0.0,
+            },
+            Velocity {
+                vel: [vx, 0.0],
+                rot: 0.0,
+            },
+        )
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut snapshots = Snapshots::new();
+        assert!(snapshots.sample(0.0, 1.0).is_none());
+
+        // Out of order
+        let (pos, vel) = state(2.0, 10.0);
+        snapshots.insert(0.2, pos, vel);
+        let (pos, vel) = state(1.0, 10.0);
+        snapshots.insert(0.1, pos, vel);
+
+        // Interpolate
+        let (pos, _) = snapshots.sample(0.125, 1.0).unwrap();
+        assert!((pos.pos[0] - 1.25).abs() < 1e-4);
+        // Extrapolate
+        let (pos, vel) = snapshots.sample(0.3, 1.0).unwrap();
+        assert!((pos.pos[0] - 3.0).abs() < 1e-4);
+        assert_eq!(vel.vel, [10.0, 0.0]);
+        // Old snapshot was dropped
+        assert_eq!(snapshots.buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_extrapolation_limit() {
+        let mut snapshots = Snapshots::new();
+        let (pos, vel) = state(1.0, 10.0);
+        snapshots.insert(0.1, pos, vel);
+
+        // Keeps going for a while
+        let (pos, vel) = snapshots.sample(0.2, 0.3).unwrap();
+        assert!((pos.pos[0] - 2.0).abs() < 1e-4);
+        assert_eq!(vel.vel, [10.0, 0.0]);
+
+        // Then stops where the limit is
+        for &time in &[0.4, 1.0, 10.0] {
+            let (pos, vel) = snapshots.sample(time, 0.3).unwrap();
+            assert!((pos.pos[0] - 4.0).abs() < 1e-4);
+            assert_eq!(vel.vel, [0.0, 0.0]);
+        }
+
+        // And moves again with news from the server
+        let (pos, vel) = state(5.0, 10.0);
+        snapshots.insert(0.5, pos, vel);
+        let (pos, _) = snapshots.sample(0.6, 0.3).unwrap();
+        assert!((pos.pos[0] - 6.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut clock = PlaybackClock::new();
+        clock.tick_length = 0.1;
+        assert_eq!(clock.advance(0.01, 0.2), None);
+
+        // Wrapping ticks
+        assert_eq!(clock.tick_time(u32::MAX), 0.0);
+        assert!((clock.tick_time(1) - 0.2).abs() < 1e-6);
+        assert!((clock.tick_time(0) - 0.1).abs() < 1e-6);
+
+        // Starts at the target, then runs close to real time
+        assert_eq!(clock.advance(0.01, 0.2), Some(0.0));
+        let t = clock.advance(0.01, 0.2).unwrap();
+        assert!(t > 0.0 && t < 0.02);
+    }
+}
@@ -0,0 +1,204 @@
+//! Lag compensation of projectile hits.
+//!
+//! Clients show the world a little in the past, and their controls take a
+//! while to reach the server. By the time a shot is fired on the server, the
+//! target has moved on from where the client aimed. To make up for it, the
+//! server keeps the recent positions of `Blocky` entities, and checks the
+//! first moments of a projectile's flight against the world as the shooter
+//! saw it.
+
+use specs::{Component, Entity, VecStorage};
+use std::collections::VecDeque;
+use vecmath::*;
+
+use crate::blocks::Blocky;
+use crate::physics::{find_collision_tree_box, DetectCollision, Position};
+
+/// Most ticks a shot is taken back in time, so players with bad connections
+/// can't hit things long gone.
+pub const MAX_REWIND: u32 = 8;
+
+/// Server component keeping the recent positions of an entity.
+#[derive(Default)]
+pub struct PositionHistory {
+    positions: VecDeque<(u32, Position)>,
+}
+
+impl Component for PositionHistory {
+    type Storage = VecStorage<Self>;
+}
+
+impl PositionHistory {
+    pub fn new() -> PositionHistory {
+        PositionHistory {
+            positions: VecDeque::new(),
+        }
+    }
+
+    /// Records the position of the entity at a tick.
+    pub fn record(&mut self, tick: u32, pos: &Position) {
+        self.positions.push_back((tick, pos.clone()));
+        while self.positions.len() > MAX_REWIND as usize + 1 {
+            self.positions.pop_front();
+        }
+    }
+
+    /// Gets the position the entity had at a tick, if it is recent enough.
+    pub fn at(&self, tick: u32) -> Option<&Position> {
+        self.positions
+            .iter()
+            .find(|&&(t, _)| t == tick)
+            .map(|(_, pos)| pos)
+    }
+}
+
+/// An entity a projectile can hit, with where it was and where it is now.
+pub struct Target<'a> {
+    pub entity: Entity,
+    pub history: &'a PositionHistory,
+    pub pos: &'a Position,
+    pub blocky: &'a Blocky,
+}
+
+/// Moves a projectile fired `rewind` ticks ago, as seen by its shooter, to
+/// where it would be now. `step` is how far it goes in a tick.
+///
+/// If it would have hit something in that time, it is put right in front of
+/// the spot it hit, where that entity is now, so the collision happens on
+/// the next tick.
+pub fn rewind_projectile(
+    pos: &Position,
+    step: [f32; 2],
+    col: &DetectCollision,
+    shooter: Entity,
+    tick: u32,
+    rewind: u32,
+    targets: &[Target],
+) -> Position {
+    for k in 0..rewind {
+        let past_tick = tick.wrapping_sub(rewind - k);
+        let proj = Position {
+            pos: vec2_add(pos.pos, vec2_scale(step, k as f32)),
+            rot: pos.rot,
+        };
+        for target in targets {
+            if target.entity == shooter || target.blocky.blocks.is_empty() {
+                continue;
+            }
+            let past = match target.history.at(past_tick) {
+                Some(p) => p,
+                None => continue,
+            };
+            let rad = col.radius + target.blocky.radius;
+            if vec2_square_len(vec2_sub(proj.pos, past.pos)) > rad * rad {
+                continue;
+            }
+            let hit = match find_collision_tree_box(
+                &proj,
+                &col.bounding_box,
+                past,
+                &target.blocky.tree,
+                0,
+            ) {
+                Some(h) => h,
+                None => continue,
+            };
+
+            // Where that is on the target, then where that is now
+            let (s, c) = past.rot.sin_cos();
+            let rel = vec2_sub(hit.location, past.pos);
+            let local = [rel[0] * c + rel[1] * s, -rel[0] * s + rel[1] * c];
+            let (s, c) = target.pos.rot.sin_cos();
+            let now = vec2_add(
+                target.pos.pos,
+                [local[0] * c - local[1] * s, local[0] * s + local[1] * c],
+            );
+            return Position {
+                pos: vec2_sub(now, step),
+                rot: pos.rot,
+            };
+        }
+    }
+    Position {
+        pos: vec2_add(pos.pos, vec2_scale(step, rewind as f32)),
+        rot: pos.rot,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Builder, World, WorldExt};
+
+    use super::{rewind_projectile, PositionHistory, Target};
+    use crate::blocks::{Block, BlockInner, Blocky};
+    use crate::guns::ProjectileType;
+    use crate::physics::{DetectCollision, Position};
+
+    fn at(x: f32) -> Position {
+        Position {
+            pos: [x, 0.0],
+            rot: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut world = World::new();
+        let shooter = world.create_entity().build();
+        let entity = world.create_entity().build();
+        let (blocky, _) =
+            Blocky::new(vec![([0.0, 0.0], Block::new(BlockInner::Armor))]);
+        let bounding_box = ProjectileType::Plasma.bounds();
+        let col = DetectCollision {
+            radius: bounding_box.compute_sq_radius().sqrt(),
+            bounding_box,
+            mass: None,
+            ignore: None,
+        };
+
+        // Target sat at 10 while the shooter was looking, then moved away
+        let mut history = PositionHistory::new();
+        for tick in 1..4 {
+            history.record(tick, &at(10.0));
+        }
+        let now = at(30.0);
+        history.record(4, &now);
+        let targets = vec![Target {
+            entity,
+            history: &history,
+            pos: &now,
+            blocky: &blocky,
+        }];
+
+        // Hit where the target was, put in front of where it is now
+        let pos = rewind_projectile(
+            &at(0.0),
+            [5.0, 0.0],
+            &col,
+            shooter,
+            4,
+            3,
+            &targets,
+        );
+        assert!(pos.pos[0] > 20.0 && pos.pos[0] < 30.0);
+
+        // Shooter doesn't hit itself
+        let pos = rewind_projectile(
+            &at(0.0),
+            [5.0, 0.0],
+            &col,
+            entity,
+            4,
+            3,
+            &targets,
+        );
+        assert_eq!(pos.pos, [15.0, 0.0]);
+
+        // Too far back, not in the history anymore
+        for tick in 5..20 {
+            history.record(tick, &now);
+        }
+        assert!(history.at(1).is_none());
+        assert!(history.at(19).is_some());
+    }
+}
@@ -0,0 +1,126 @@
+//! Protection of the server against misbehaving clients.
+//!
+//! Each address gets a budget of packets that refills over time, and
+//! whatever the server throws away is counted in `Rejected`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Counters of what the server rejected, available as a resource.
+#[derive(Debug, Default, Clone)]
+pub struct Rejected {
+    /// Packets or messages that couldn't be decoded.
+    pub malformed: u64,
+    /// Packets with a client ID but the wrong session token.
+    pub bad_token: u64,
+    /// Packets over an address' rate limit.
+    pub rate_limited: u64,
+    /// Clients turned away because the server was full, or because their
+    /// address already had a client.
+    pub refused: u64,
+    /// Ship controls with values out of range.
+    pub invalid_controls: u64,
+    /// Chat messages over a client's rate limit.
+    pub chat_limited: u64,
+}
+
+impl Rejected {
+    pub fn total(&self) -> u64 {
+        self.malformed
+            + self.bad_token
+            + self.rate_limited
+            + self.refused
+            + self.invalid_controls
+            + self.chat_limited
+    }
+}
+
+struct Bucket {
+    tokens: f32,
+    last: Instant,
+}
+
+/// Limits the rate of packets from each address, or of anything else from
+/// each of something.
+///
+/// Addresses can send `rate` packets per second, with bursts of up to
+/// `burst`, a second's worth unless set otherwise.
+pub struct RateLimiter<A: Eq + Hash> {
+    rate: f32,
+    burst: f32,
+    buckets: HashMap<A, Bucket>,
+}
+
+impl<A: Eq + Hash> RateLimiter<A> {
+    pub fn new(rate: f32) -> RateLimiter<A> {
+        RateLimiter::with_burst(rate, rate)
+    }
+
+    pub fn with_burst(rate: f32, burst: f32) -> RateLimiter<A> {
+        RateLimiter {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Whether a packet from that address should be accepted.
+    pub fn allow(&mut self, address: A, now: Instant) -> bool {
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(address).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f32() * rate).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets the addresses that have been quiet long enough to be back to
+    /// a full budget.
+    pub fn cleanup(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.last);
+            bucket.tokens + elapsed.as_secs_f32() * rate < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = RateLimiter::new(10.0);
+        let now = Instant::now();
+
+        // Burst, then nothing
+        let allowed = (0..30).filter(|_| limiter.allow(1, now)).count();
+        assert_eq!(allowed, 10);
+        assert!(!limiter.allow(1, now));
+
+        // Other addresses are unaffected
+        assert!(limiter.allow(2, now));
+
+        // Refills over time
+        let later = now + Duration::from_millis(500);
+        let allowed = (0..30).filter(|_| limiter.allow(1, later)).count();
+        assert_eq!(allowed, 5);
+
+        // Quiet addresses get forgotten
+        limiter.cleanup(now + Duration::from_secs(5));
+        assert!(limiter.buckets.is_empty());
+    }
+}
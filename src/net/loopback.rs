@@ -0,0 +1,782 @@
+//! In-memory transport, for tests and local simulations.
+//!
+//! A `LoopbackServer` hands out a `Connector`, which can create any number of
+//! `LoopbackClient`s talking to it through channels. Each link can simulate
+//! latency, jitter, packet loss and reordering.
+
+use rand::{self, Rng};
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Client, Server};
+
+/// Network conditions simulated on a link, in both directions.
+#[derive(Debug, Clone)]
+pub struct Conditions {
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// Maximum random delay added on top of `latency`.
+    pub jitter: Duration,
+    /// Probability that a packet gets dropped, between 0 and 1.
+    pub loss: f32,
+    /// Probability that a packet gets held back so that later packets
+    /// overtake it, between 0 and 1.
+    pub reorder: f32,
+}
+
+impl Default for Conditions {
+    fn default() -> Conditions {
+        Conditions {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            reorder: 0.0,
+        }
+    }
+}
+
+impl Conditions {
+    /// Picks the delivery time of a packet sent now, or None if it is lost.
+    fn schedule(&self) -> Option<Instant> {
+        let mut rng = rand::thread_rng();
+        if self.loss > 0.0 && rng.gen::<f32>() < self.loss {
+            return None;
+        }
+        let mut delay = self.latency;
+        if self.jitter > Duration::from_millis(0) {
+            delay += self.jitter.mul_f32(rng.gen::<f32>());
+        }
+        if self.reorder > 0.0 && rng.gen::<f32>() < self.reorder {
+            delay += self.latency + self.jitter + Duration::from_millis(1);
+        }
+        Some(Instant::now() + delay)
+    }
+}
+
+/// Address of a client on the loopback server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoopbackAddress(u32);
+
+impl fmt::Display for LoopbackAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "loopback:{}", self.0)
+    }
+}
+
+struct Packet<A> {
+    deliver_at: Instant,
+    data: Vec<u8>,
+    source: A,
+}
+
+/// Receiving end of a link, holding packets until their delivery time.
+struct Inbox<A> {
+    receiver: Receiver<Packet<A>>,
+    pending: RefCell<Vec<Packet<A>>>,
+}
+
+impl<A> Inbox<A> {
+    fn new(receiver: Receiver<Packet<A>>) -> Inbox<A> {
+        Inbox {
+            receiver,
+            pending: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, A)> {
+        let mut pending = self.pending.borrow_mut();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(p) => pending.push(p),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        // Deliver the earliest packet that is due
+        let now = Instant::now();
+        let next = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.deliver_at <= now)
+            .min_by_key(|(_, p)| p.deliver_at)
+            .map(|(i, _)| i);
+        match next {
+            Some(i) => {
+                let packet = pending.remove(i);
+                // Truncate like a datagram socket would
+                let len = packet.data.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&packet.data[..len]);
+                Ok((len, packet.source))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+struct Link {
+    sender: Sender<Packet<()>>,
+    conditions: Conditions,
+}
+
+/// Clients connected to a server, shared with its `Connector`s.
+struct Registry {
+    server: Sender<Packet<LoopbackAddress>>,
+    clients: Vec<Link>,
+}
+
+/// Sends a packet on a channel, unless the simulated link drops it.
+fn send<A>(
+    sender: &Sender<Packet<A>>,
+    conditions: &Conditions,
+    dropped: &AtomicUsize,
+    msg: &[u8],
+    source: A,
+) -> io::Result<usize> {
+    match conditions.schedule() {
+        Some(deliver_at) => {
+            // Like UDP, sending to a peer that is gone is not an error
+            let _ = sender.send(Packet {
+                deliver_at,
+                data: msg.into(),
+                source,
+            });
+        }
+        None => {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    Ok(msg.len())
+}
+
+pub struct LoopbackServer {
+    inbox: Inbox<LoopbackAddress>,
+    registry: Arc<Mutex<Registry>>,
+    /// Packets lost on any link, in either direction.
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Default for LoopbackServer {
+    fn default() -> LoopbackServer {
+        LoopbackServer::new()
+    }
+}
+
+impl LoopbackServer {
+    pub fn new() -> LoopbackServer {
+        let (sender, receiver) = channel();
+        LoopbackServer {
+            inbox: Inbox::new(receiver),
+            registry: Arc::new(Mutex::new(Registry {
+                server: sender,
+                clients: Vec::new(),
+            })),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Gets a `Connector`, to create clients once the server is running.
+    pub fn connector(&self) -> Connector {
+        Connector {
+            registry: self.registry.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl Server for LoopbackServer {
+    type Address = LoopbackAddress;
+
+    fn send(&self, msg: &[u8], addr: &LoopbackAddress) -> io::Result<usize> {
+        let registry = self.registry.lock().unwrap();
+        match registry.clients.get(addr.0 as usize) {
+            Some(link) => {
+                send(&link.sender, &link.conditions, &self.dropped, msg, ())
+            }
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn recv(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, LoopbackAddress)> {
+        self.inbox.recv(buffer)
+    }
+}
+
+/// Creates clients for a `LoopbackServer`.
+#[derive(Clone)]
+pub struct Connector {
+    registry: Arc<Mutex<Registry>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Connector {
+    /// Creates a new client, over a link with the given conditions.
+    pub fn connect(&self, conditions: Conditions) -> LoopbackClient {
+        let (sender, receiver) = channel();
+        let mut registry = self.registry.lock().unwrap();
+        let address = LoopbackAddress(registry.clients.len() as u32);
+        registry.clients.push(Link {
+            sender,
+            conditions: conditions.clone(),
+        });
+        LoopbackClient {
+            address,
+            conditions,
+            server: registry.server.clone(),
+            inbox: Inbox::new(receiver),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    /// Number of packets the simulated links lost so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub struct LoopbackClient {
+    address: LoopbackAddress,
+    conditions: Conditions,
+    server: Sender<Packet<LoopbackAddress>>,
+    inbox: Inbox<()>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl LoopbackClient {
+    /// The address the server sees this client as.
+    pub fn address(&self) -> LoopbackAddress {
+        self.address
+    }
+}
+
+impl Client for LoopbackClient {
+    fn send(&self, msg: &[u8]) -> io::Result<usize> {
+        send(
+            &self.server,
+            &self.conditions,
+            &self.dropped,
+            msg,
+            self.address,
+        )
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.inbox.recv(buffer).map(|(len, ())| len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Join, WorldExt};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::{Conditions, Connector, LoopbackServer};
+    use crate::asteroid::Asteroid;
+    use crate::{Game, WorldConfig};
+    use crate::guns::Projectile;
+    use crate::input::{Input, Press};
+    use crate::net::{Admin, Chat, ClientConfig, NetStats, Rejected,
+                     Replicated, Server, ServerConfig, WordFilter};
+    use crate::physics::{LocalControl, Position, Velocity};
+    use crate::ship::Ship;
+
+    /// Starts a server, with clients connected over a perfect link.
+    fn start(
+        config: ServerConfig,
+        clients: Vec<ClientConfig>,
+    ) -> (Game, Vec<Game>, Connector) {
+        let server = LoopbackServer::new();
+        let connector = server.connector();
+        let server = Game::new_server(server, config);
+        let clients = clients
+            .into_iter()
+            .map(|config| {
+                Game::new_client(connector.connect(Default::default()), config)
+            })
+            .collect();
+        (server, clients, connector)
+    }
+
+    fn run(server: &mut Game, clients: &mut [Game], frames: usize) {
+        for _ in 0..frames {
+            server.update(0.080);
+            for client in clients.iter_mut() {
+                client.update(0.080);
+            }
+        }
+    }
+
+    /// Runs frames for some real time, so that packets make it through
+    /// delayed links and lost ones get sent again.
+    fn run_for(server: &mut Game, clients: &mut [Game], duration: Duration) {
+        let step = Duration::from_millis(5);
+        for _ in 0..duration.as_millis() / step.as_millis() {
+            run(server, clients, 1);
+            sleep(step);
+        }
+    }
+
+    fn count_ships(game: &Game) -> (usize, usize) {
+        let ships = game.world.read_component::<Ship>();
+        let local = game.world.read_component::<LocalControl>();
+        ((&ships).join().count(), (&ships, &local).join().count())
+    }
+
+    #[test]
+    fn test_multiple_clients() {
+        // Far enough to see the asteroids coming in
+        let config = ServerConfig {
+            interest_radius: 1000.0,
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) =
+            start(config, vec![Default::default(); 2]);
+        run(&mut server, &mut clients, 5);
+
+        assert_eq!(count_ships(&server).0, 2);
+        for client in &clients {
+            assert_eq!(count_ships(client), (2, 1));
+            // Spawned with their kind
+            let asteroids = client.world.read_component::<Asteroid>();
+            assert!((&asteroids).join().count() > 0);
+        }
+
+        // Ship goes away with its client
+        clients.pop();
+        run(&mut server, &mut clients, 3);
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+
+        // Clients follow the size of the world
+        server.world.write_resource::<WorldConfig>().size = 250.0;
+        run(&mut server, &mut clients, 2);
+        let world = clients[0].world.read_resource::<WorldConfig>();
+        assert_eq!(world.size, 250.0);
+    }
+
+    #[test]
+    fn test_bad_conditions() {
+        let (mut server, mut clients, connector) =
+            start(ServerConfig::default(), vec![]);
+        let conditions = Conditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            loss: 0.0,
+            reorder: 0.3,
+        };
+        clients.push(Game::new_client(
+            connector.connect(conditions),
+            ClientConfig::default(),
+        ));
+        run_for(&mut server, &mut clients, Duration::from_millis(300));
+
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+
+        // Both sides measured the link
+        let stats = clients[0].world.read_resource::<NetStats>();
+        assert!(stats.rtt > 0.0);
+        assert!(stats.bytes_in > 0.0 && stats.bytes_out > 0.0);
+        assert!(stats.messages_in.contains_key("EntityUpdate"));
+        let stats = server.world.read_resource::<NetStats>();
+        assert!(stats.rtt > 0.0);
+        assert!(stats.messages_in.contains_key("EntityUpdate"));
+    }
+
+    #[test]
+    fn test_packet_loss() {
+        let config = ServerConfig {
+            interest_radius: 1000.0,
+            ..Default::default()
+        };
+        let (mut server, mut clients, connector) = start(config, vec![]);
+        let conditions = Conditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            loss: 0.3,
+            reorder: 0.1,
+        };
+        clients.push(Game::new_client(
+            connector.connect(conditions),
+            ClientConfig::default(),
+        ));
+        let positions = |game: &Game| -> Vec<_> {
+            let asteroids = game.world.read_component::<Asteroid>();
+            let position = game.world.read_component::<Position>();
+            (&asteroids, &position).join().map(|(_, p)| p.pos).collect()
+        };
+        run_for(&mut server, &mut clients, Duration::from_millis(500));
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+        let before = positions(&clients[0]);
+        assert!(!before.is_empty());
+
+        // Things keep moving, even though packets get lost
+        run_for(&mut server, &mut clients, Duration::from_millis(250));
+        assert!(connector.dropped() > 0);
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+        assert_ne!(positions(&clients[0]), before);
+    }
+
+    #[test]
+    fn test_lost_hello() {
+        let server = LoopbackServer::new();
+        let connector = server.connector();
+        let conditions = Conditions {
+            loss: 0.2,
+            ..Default::default()
+        };
+        let client = Game::new_client(
+            connector.connect(conditions),
+            ClientConfig::default(),
+        );
+
+        // First hello never makes it
+        let mut buffer = [0; 1500];
+        while server.recv(&mut buffer).is_ok() {}
+        let mut server = Game::new_server(server, ServerConfig::default());
+
+        // Client says hello again, and gets in despite the losses
+        let mut clients = vec![client];
+        run_for(&mut server, &mut clients, Duration::from_secs(1));
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+    }
+
+    #[test]
+    fn test_interest() {
+        let config = ServerConfig {
+            interest_radius: 0.0,
+            interest_margin: 0.0,
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) =
+            start(config, vec![Default::default(); 2]);
+        run(&mut server, &mut clients, 5);
+
+        // Only told about their own ship
+        assert_eq!(count_ships(&server).0, 2);
+        for client in &clients {
+            assert_eq!(count_ships(client), (1, 1));
+            let asteroids = client.world.read_component::<Asteroid>();
+            assert_eq!((&asteroids).join().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_interest_return() {
+        let config = ServerConfig {
+            interest_radius: 20.0,
+            interest_margin: 0.0,
+            ..Default::default()
+        };
+        let (mut server, mut clients, connector) = start(config, vec![]);
+        let conditions = Conditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(10),
+            loss: 0.2,
+            reorder: 0.5,
+        };
+        clients.push(Game::new_client(
+            connector.connect(conditions),
+            ClientConfig::default(),
+        ));
+        clients.push(Game::new_client(
+            connector.connect(Default::default()),
+            ClientConfig::default(),
+        ));
+        run_for(&mut server, &mut clients, Duration::from_millis(500));
+        assert_eq!(count_ships(&server).0, 2);
+
+        // Moves the second ship next to the first one, or away from it
+        let place = |server: &mut Game, offset: f32| {
+            let ships = server.world.read_component::<Ship>();
+            let mut pos = server.world.write_component::<Position>();
+            let mut vel = server.world.write_component::<Velocity>();
+            let entities = server.world.entities();
+            let ents: Vec<_> =
+                (&entities, &ships).join().map(|(e, _)| e).collect();
+            let [x, y] = pos.get(ents[0]).unwrap().pos;
+            pos.get_mut(ents[1]).unwrap().pos = [x + offset, y];
+            vel.get_mut(ents[1]).unwrap().vel = [0.0, 0.0];
+        };
+
+        // Second ship quickly comes and goes, so that spawns get sent while
+        // deletes are still getting through, then stays close and leaves
+        for i in 0..40 {
+            place(&mut server, if i / 2 % 2 == 0 { 500.0 } else { 10.0 });
+            run_for(&mut server, &mut clients, Duration::from_millis(10));
+        }
+        for &(offset, expected) in &[(10.0, 2), (500.0, 1)] {
+            for _ in 0..60 {
+                place(&mut server, offset);
+                run_for(&mut server, &mut clients, Duration::from_millis(10));
+            }
+            assert_eq!(count_ships(&clients[0]), (expected, 1));
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let config = ServerConfig {
+            client_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = ClientConfig {
+            reconnect_after: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) = start(config, vec![client]);
+        run(&mut server, &mut clients, 5);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+
+        // Client goes quiet, server keeps its ship around
+        run_for(&mut server, &mut [], Duration::from_millis(200));
+        assert_eq!(count_ships(&server).0, 1);
+
+        // Client stops hearing from the server, and starts over
+        for _ in 0..5 {
+            clients[0].update(0.080);
+        }
+        assert_eq!(count_ships(&clients[0]), (0, 0));
+
+        // And gets its ship back
+        run(&mut server, &mut clients, 5);
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+    }
+
+    #[test]
+    fn test_session_expired() {
+        let config = ServerConfig {
+            client_timeout: Duration::from_millis(100),
+            reconnect_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = ClientConfig {
+            reconnect_after: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) = start(config, vec![client]);
+        run(&mut server, &mut clients, 5);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+
+        // Client stays away for too long, server gives up on it
+        run_for(&mut server, &mut [], Duration::from_millis(400));
+        assert_eq!(count_ships(&server).0, 0);
+
+        // Client tries to come back, is told to start over, and joins again
+        for _ in 0..5 {
+            clients[0].update(0.080);
+        }
+        run(&mut server, &mut clients, 15);
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+    }
+
+    #[test]
+    fn test_server_full() {
+        let config = ServerConfig {
+            max_clients: 1,
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) =
+            start(config, vec![Default::default(); 2]);
+        run(&mut server, &mut clients, 5);
+
+        // Second one got turned away
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[1]), (0, 0));
+        assert_eq!(server.world.read_resource::<Rejected>().refused, 1);
+    }
+
+    #[test]
+    fn test_admin() {
+        let (mut server, mut clients, _) =
+            start(ServerConfig::default(), vec![Default::default(); 2]);
+        run(&mut server, &mut clients, 5);
+        let listed = server.world.read_resource::<Admin>().clients.clone();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().all(|c| c.ships == 1));
+
+        // Kicked client loses its ship, and doesn't come back
+        server.world.write_resource::<Admin>().kick(listed[0].client_id);
+        run(&mut server, &mut clients, 5);
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(server.world.read_resource::<Admin>().clients.len(), 1);
+
+        // Everyone gets disconnected on shutdown
+        server.world.write_resource::<Admin>().shutdown();
+        run(&mut server, &mut clients, 5);
+        assert_eq!(count_ships(&server).0, 0);
+        assert!(server.world.read_resource::<Admin>().clients.is_empty());
+    }
+
+    #[test]
+    fn test_spectator() {
+        let config = ServerConfig {
+            interest_radius: 10.0,
+            ..Default::default()
+        };
+        let spectate = ClientConfig {
+            spectate: true,
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) =
+            start(config, vec![ClientConfig::default(), spectate]);
+        run(&mut server, &mut clients, 5);
+
+        // No ship for the spectator, but it sees everything
+        assert_eq!(count_ships(&server).0, 1);
+        assert_eq!(count_ships(&clients[1]), (1, 0));
+        let asteroids = |game: &Game| {
+            let asteroids = game.world.read_component::<Asteroid>();
+            (&asteroids).join().count()
+        };
+        assert!(asteroids(&clients[1]) > asteroids(&clients[0]));
+        {
+            let admin = server.world.read_resource::<Admin>();
+            let spectators: Vec<bool> =
+                admin.clients.iter().map(|c| c.spectator).collect();
+            assert_eq!(spectators, vec![false, true]);
+        }
+
+        // Asteroids keep moving long after they were spawned
+        let positions = |game: &Game| -> HashMap<_, _> {
+            let asteroids = game.world.read_component::<Asteroid>();
+            let replicated = game.world.read_component::<Replicated>();
+            let position = game.world.read_component::<Position>();
+            (&asteroids, &replicated, &position)
+                .join()
+                .map(|(_, r, p)| (r.id, p.pos))
+                .collect()
+        };
+        run(&mut server, &mut clients, 50);
+        let before = (positions(&server), positions(&clients[1]));
+        run(&mut server, &mut clients, 20);
+        let after = (positions(&server), positions(&clients[1]));
+        let moving: Vec<_> = before
+            .0
+            .iter()
+            .filter(|&(id, pos)| after.0.get(id).map_or(false, |p| p != pos))
+            .map(|(id, _)| id)
+            .filter(|&id| {
+                before.1.contains_key(id) && after.1.contains_key(id)
+            })
+            .collect();
+        assert!(!moving.is_empty());
+        for id in moving {
+            assert_ne!(before.1[id], after.1[id]);
+        }
+    }
+
+    #[test]
+    fn test_chat() {
+        let config = ServerConfig {
+            chat_rate: 0.01,
+            chat_burst: 2.0,
+            chat_filter: Some(Arc::new(WordFilter::new(vec!["darn"]))),
+            ..Default::default()
+        };
+        let named = ClientConfig {
+            name: " Alice\n".to_owned(),
+            ..Default::default()
+        };
+        let (mut server, mut clients, _) =
+            start(config, vec![named, ClientConfig::default()]);
+        run(&mut server, &mut clients, 5);
+
+        {
+            let mut chat = clients[0].world.write_resource::<Chat>();
+            chat.send("darn, hello");
+            chat.send("  ");
+            chat.send("second");
+            chat.send("over the limit");
+        }
+        run(&mut server, &mut clients, 5);
+        clients[1].world.write_resource::<Chat>().send("hi");
+        run(&mut server, &mut clients, 5);
+
+        // Everyone sees the same messages, filtered, with who sent them
+        let log = |game: &Game| {
+            let chat = game.world.read_resource::<Chat>();
+            chat.log
+                .iter()
+                .map(|line| format!("{}: {}", line.sender, line.text))
+                .collect::<Vec<_>>()
+        };
+        let expected =
+            vec!["Alice: ****, hello", "Alice: second", "Player 2: hi"];
+        assert_eq!(log(&server), expected);
+        assert_eq!(log(&clients[0]), expected);
+        assert_eq!(log(&clients[1]), expected);
+        assert_eq!(server.world.read_resource::<Rejected>().chat_limited, 1);
+    }
+
+    fn local_velocity(game: &Game) -> Option<[f32; 2]> {
+        let vel = game.world.read_component::<Velocity>();
+        let local = game.world.read_component::<LocalControl>();
+        (&vel, &local).join().next().map(|(v, _)| v.vel)
+    }
+
+    #[test]
+    fn test_prediction() {
+        let (mut server, mut clients, connector) =
+            start(ServerConfig::default(), vec![]);
+        let conditions = Conditions {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        };
+        clients.push(Game::new_client(
+            connector.connect(conditions),
+            ClientConfig::default(),
+        ));
+        run_for(&mut server, &mut clients, Duration::from_millis(500));
+        assert_eq!(local_velocity(&clients[0]), Some([0.0, 0.0]));
+
+        // Ship moves right away, without waiting for the server
+        clients[0].world.write_resource::<Input>().movement = [1.0, 0.0];
+        clients[0].update(0.020);
+        assert!(local_velocity(&clients[0]).unwrap()[0] > 0.0);
+
+        // And keeps its course as the server catches up
+        for _ in 0..5 {
+            run_for(&mut server, &mut clients, Duration::from_millis(20));
+            assert!(local_velocity(&clients[0]).unwrap()[0] > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_shooter() {
+        let (mut server, mut clients, _) =
+            start(ServerConfig::default(), vec![Default::default()]);
+        run(&mut server, &mut clients, 5);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+
+        // Shots come from our ship, like on the server
+        clients[0].world.write_resource::<Input>().fire = Press::PRESSED;
+        let mut shots = 0;
+        for _ in 0..10 {
+            run(&mut server, &mut clients, 1);
+            let world = &clients[0].world;
+            let local = world.read_component::<LocalControl>();
+            let projectiles = world.read_component::<Projectile>();
+            let ship = (&world.entities(), &local).join().next().unwrap().0;
+            for projectile in (&projectiles).join() {
+                assert_eq!(projectile.shooter, ship);
+                shots += 1;
+            }
+        }
+        assert!(shots > 0);
+    }
+}
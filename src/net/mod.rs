@@ -1,225 +1,409 @@
 //! Network code.
 
+mod admin;
 mod base;
+mod chat;
+mod codec;
+mod delta;
+pub mod discovery;
+mod interpolation;
+mod lag;
+mod limits;
+pub mod loopback;
+mod packet;
+mod prediction;
+mod priority;
+mod reliable;
+pub mod rooms;
+mod stats;
 pub mod udp;
 
 use byteorder::{self, ReadBytesExt, WriteBytesExt};
 use log::{info, warn};
-use specs::{Entities, Read, Join, LazyUpdate, ReadStorage, System,
-            WriteStorage};
+use specs::world::EntitiesRes;
+use specs::{Entities, Entity, Read, Join, LazyUpdate, ReadStorage, System,
+            Write, WriteStorage};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::io::{self, Cursor, Write};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::hash::Hash;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vecmath::{vec2_len, vec2_scale};
 
 use crate::asteroid::Asteroid;
-use crate::guns::{Projectile, ProjectileType};
+use crate::blocks::Blocky;
+use crate::guns::Projectile;
 use crate::particles::Effect;
-use crate::physics::{LocalControl, Position, Velocity};
+use crate::physics::{DeltaTime, DetectCollision, LocalControl, Position,
+                     Velocity};
 use crate::ship::Ship;
+use crate::WorldConfig;
 
+use self::codec::{negotiate_version, read_blocky, time_decode, time_encode,
+                  write_blocky, Controls, EntityKind, EntityState, Message};
+
+pub use self::admin::{Admin, ClientSummary};
+use self::admin::AdminCommand;
 pub use self::base::{Replicated, Delete, Dirty, ClientControlled};
+pub use self::chat::{Chat, ChatFilter, ChatLine, WordFilter};
+use self::chat::sanitize;
+use self::delta::{Baselines, History};
+use self::interpolation::PlaybackClock;
+use self::lag::{rewind_projectile, Target, MAX_REWIND};
+use self::limits::RateLimiter;
+use self::packet::{Packer, Unpacker, MAX_PACKET_SIZE};
+use self::prediction::{seq_newer, Prediction};
+use self::priority::{priority, Priorities, Update};
+use self::reliable::{ReliableReceiver, ReliableSender};
+pub use self::codec::{DecodeError, DisconnectReason, MIN_PROTOCOL_VERSION,
+                      PROTOCOL_VERSION};
+pub use self::interpolation::Snapshots;
+pub use self::lag::PositionHistory;
+pub use self::limits::Rejected;
+pub use self::stats::NetStats;
+use self::stats::LinkStats;
 
 type ORDER = byteorder::BigEndian;
 
-fn time_encode(d: Duration) -> u32 {
-    (d.as_secs() as u32).wrapping_shl(10) | d.subsec_nanos().wrapping_shr(22)
+/// Warns if a Result is an error.
+fn chk<T>(res: Result<T, io::Error>) {
+    match res {
+        Ok(_) => {}
+        Err(e) => warn!("Network error: {}", e),
+    }
 }
 
-fn time_decode(b: u32) -> Duration {
-    let secs = (b as u64).wrapping_shr(10);
-    let nanos = b.wrapping_shl(22);
-    Duration::new(secs, nanos)
+/// ID of an entity on the server, that clients know it by.
+fn replicated_id(ent: Entity) -> u64 {
+    (ent.gen().id() as u64) << 32 | ent.id() as u64
 }
 
-fn write_float<W: io::Write>(mut writer: W, v: f32) {
-    let v = v as f32;
-    assert_eq!(
-        writer
-            .write(&unsafe { ::std::mem::transmute::<f32, [u8; 4]>(v) })
-            .unwrap(),
-        4
-    );
+pub trait Server: Send + 'static {
+    type Address: Clone + Display + Eq + Hash + Send;
+
+    fn send(&self, msg: &[u8], addr: &Self::Address) -> io::Result<usize>;
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, Self::Address)>;
 }
 
-fn read_float<R: io::Read>(mut reader: R) -> f32 {
-    let mut v = [0u8; 4];
-    assert_eq!(reader.read(&mut v).unwrap(), 4);
-    let v = unsafe { ::std::mem::transmute::<[u8; 4], f32>(v) };
-    v as f32
+pub trait Client: Send + 'static {
+    fn send(&self, msg: &[u8]) -> io::Result<usize>;
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize>;
 }
 
-/// The message exchanged by server and clients.
-enum Message {
-    /// Message sent by a client to introduce itself.
-    ///
-    /// The server will reply with ServerHello.
-    ClientHello,
-    /// Message sent by the server to accept a client, and assign it a client
-    /// ID.
-    ServerHello(u64),
-    /// Ping request, other side should send bytes back as Pong.
-    Ping(u32),
-    /// Pong reply, with the bytes from the Ping request.
-    Pong(u32),
-    /// Message sent by the server to give the client an entity to
-    /// control.
-    StartEntityControl(u64),
-    /// Entity update, from either side.
-    ///
-    /// The server sends full entity updates that the client applies. The
-    /// client sends update to the controls, preceded by its secret.
-    EntityUpdate(u64, Vec<u8>),
-    /// Entity deleted, from server.
-    EntityDelete(u64),
+/// Interval at which the server pings clients, and clients the server.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval at which clients say hello again, until the server answers.
+const HELLO_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Interval at which the `NetStats` resource is updated, in seconds.
+const STATS_INTERVAL: f32 = 1.0;
+
+/// Interval at which the server logs the network statistics.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Most entity acknowledgements sent in a message.
+const MAX_ACKS_PER_MESSAGE: usize = 64;
+
+/// Number of times `Disconnect` is sent to a client that is dropped, as it
+/// won't be around to get it again if it gets lost.
+const DISCONNECT_COPIES: usize = 3;
+
+/// Longest player name, in characters.
+const MAX_PLAYER_NAME: usize = 24;
+
+/// Distance spectators are considered to be from every entity. They have no
+/// ship to measure it from, but still need the whole world kept up to date.
+const SPECTATOR_DISTANCE: f32 = 20.0;
+
+/// Settings for the network server.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Name given to clients looking for servers.
+    pub name: String,
+    /// Map name given to clients looking for servers.
+    pub map: String,
+    /// Clients that haven't answered a ping in that long get dropped.
+    pub client_timeout: Duration,
+    /// How long the ships of clients that timed out are kept, for them to
+    /// reconnect.
+    pub reconnect_timeout: Duration,
+    /// Distance from its ships within which a client is told about
+    /// entities.
+    pub interest_radius: f32,
+    /// How much further entities have to go before the client is told to
+    /// forget them, so they don't flicker at the edge.
+    pub interest_margin: f32,
+    /// Bytes of entity updates sent to each client per tick. Spawns and
+    /// reliable messages are always sent, and count against it.
+    pub update_budget: usize,
+    /// Most clients connected at once.
+    pub max_clients: usize,
+    /// Most ships a client can control.
+    pub max_ships_per_client: usize,
+    /// Packets per second accepted from each address.
+    pub max_packet_rate: f32,
+    /// Room this server hosts, see `Rooms`. The IDs given to clients start
+    /// with it.
+    pub room: u32,
+    /// Longest chat message, in characters. Longer ones get cut.
+    pub max_chat_length: usize,
+    /// Chat messages per second accepted from each client.
+    pub chat_rate: f32,
+    /// Chat messages a client can send in a row, before `chat_rate` kicks
+    /// in.
+    pub chat_burst: f32,
+    /// Checks chat messages and player names, if set.
+    pub chat_filter: Option<Arc<dyn ChatFilter>>,
 }
 
-impl Message {
-    /// Parse a message from some bytes.
-    fn parse(msg: &[u8]) -> Option<Message> {
-        if msg.len() < 8 || &msg[..6] != b"SPAC\x00\x01" {
-            return None;
-        }
-        let mut rdr = Cursor::new(&msg[8..]);
-        match &msg[6..8] {
-            b"hc" => {
-                if msg.len() != 8 {
-                    info!("Invalid ClientHello length");
-                    None
-                } else {
-                    Some(Message::ClientHello)
-                }
-            }
-            b"hs" => {
-                if msg.len() != 8 + 8 {
-                    info!("Invalid ServerHello length");
-                    None
-                } else {
-                    Some(Message::ServerHello(
-                        rdr.read_u64::<ORDER>().unwrap(),
-                    ))
-                }
-            }
-            b"pi" => {
-                if msg.len() != 12 {
-                    info!("Invalid Ping length");
-                    None
-                } else {
-                    let buf = rdr.read_u32::<ORDER>().unwrap();
-                    Some(Message::Ping(buf))
-                }
-            }
-            b"po" => {
-                if msg.len() != 12 {
-                    info!("Invalid Pong length");
-                    None
-                } else {
-                    let buf = rdr.read_u32::<ORDER>().unwrap();
-                    Some(Message::Pong(buf))
-                }
-            }
-            b"ec" => {
-                if msg.len() != 8 + 8 {
-                    info!("Invalid StartEntityControl length");
-                    None
-                } else {
-                    Some(Message::StartEntityControl(
-                        rdr.read_u64::<ORDER>().unwrap(),
-                    ))
-                }
-            }
-            b"eu" => {
-                if msg.len() < 16 {
-                    info!("Invalid EntityUpdate length");
-                    None
-                } else {
-                    Some(Message::EntityUpdate(
-                        rdr.read_u64::<ORDER>().unwrap(),
-                        msg[16..].into(),
-                    ))
-                }
-            }
-            b"er" => {
-                if msg.len() != 16 {
-                    info!("Invalid EntityDelete length");
-                    None
-                } else {
-                    Some(Message::EntityDelete(
-                        rdr.read_u64::<ORDER>().unwrap(),
-                    ))
-                }
-            }
-            _ => None,
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            name: "Vigilant Steel server".to_owned(),
+            map: "asteroids".to_owned(),
+            client_timeout: Duration::from_secs(10),
+            reconnect_timeout: Duration::from_secs(60),
+            interest_radius: 80.0,
+            interest_margin: 20.0,
+            update_budget: 2400,
+            max_clients: 32,
+            max_ships_per_client: 1,
+            max_packet_rate: 200.0,
+            room: 0,
+            max_chat_length: 200,
+            chat_rate: 0.5,
+            chat_burst: 5.0,
+            chat_filter: None,
         }
     }
+}
 
-    /// Write a message into a vector of bytes.
-    fn to_bytes(&self, msg: &mut Vec<u8>) {
-        msg.extend_from_slice(b"SPAC\x00\x01");
-        match *self {
-            Message::ClientHello => msg.extend_from_slice(b"hc"),
-            Message::ServerHello(id) => {
-                msg.extend_from_slice(b"hs");
-                msg.write_u64::<ORDER>(id).unwrap();
-                assert_eq!(msg.len(), 8 + 8);
-            }
-            Message::Ping(buf) => {
-                msg.extend_from_slice(b"pi");
-                msg.write_u32::<ORDER>(buf).unwrap();
-            }
-            Message::Pong(buf) => {
-                msg.extend_from_slice(b"po");
-                msg.write_u32::<ORDER>(buf).unwrap();
-            }
-            Message::StartEntityControl(id) => {
-                msg.extend_from_slice(b"ec");
-                msg.write_u64::<ORDER>(id).unwrap();
-            }
-            Message::EntityUpdate(id, ref bytes) => {
-                msg.extend_from_slice(b"eu");
-                msg.write_u64::<ORDER>(id).unwrap();
-                msg.extend_from_slice(bytes);
-            }
-            Message::EntityDelete(id) => {
-                msg.extend_from_slice(b"er");
-                msg.write_u64::<ORDER>(id).unwrap();
+/// Settings for the network client.
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// How far behind the server entities are shown, leaving time for
+    /// updates to arrive so motion can be interpolated.
+    pub interpolation_delay: Duration,
+    /// How long without hearing from the server before trying to reconnect.
+    pub reconnect_after: Duration,
+    /// Room to join, on servers hosting several.
+    pub room: u32,
+    /// Join without a ship, only to watch.
+    pub spectate: bool,
+    /// Name shown to other players. The server picks one if empty.
+    pub name: String,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            interpolation_delay: Duration::from_millis(150),
+            reconnect_after: Duration::from_secs(3),
+            room: 0,
+            spectate: false,
+            name: String::new(),
+        }
+    }
+}
+
+pub struct ConnectedClient<A: Eq> {
+    address: A,
+    client_id: u64,
+    /// Secret the client puts on its packets, so others can't pretend to be
+    /// it.
+    token: u64,
+    /// Name shown to other players.
+    name: String,
+    last_ping: SystemTime,
+    last_pong: SystemTime,
+    /// Entities this client has been sent an `EntitySpawn` for.
+    known: HashSet<u64>,
+    /// Entities this client was told to forget, with the sequence number of
+    /// the `EntityDelete` on the reliable channel.
+    forgetting: HashMap<u64, u16>,
+    /// Positions of this client's ships, around which it gets updates.
+    focus: Vec<[f32; 2]>,
+    /// Only watching, without a ship.
+    spectator: bool,
+    baselines: Baselines,
+    priorities: Priorities,
+    reliable: ReliableSender,
+    /// Messages the client sent on the reliable channel.
+    incoming: ReliableReceiver,
+    packer: Packer,
+    unpacker: Unpacker,
+    /// Bytes queued since the last flush.
+    queued: usize,
+    link: LinkStats,
+    /// Statistics over the last interval.
+    stats: NetStats,
+}
+
+impl<A: Eq> ConnectedClient<A> {
+    fn new(
+        address: A,
+        client_id: u64,
+        token: u64,
+        name: String,
+        now: SystemTime,
+    ) -> ConnectedClient<A> {
+        ConnectedClient {
+            address,
+            client_id,
+            token,
+            name,
+            // Ping right away
+            last_ping: UNIX_EPOCH,
+            last_pong: now,
+            known: HashSet::new(),
+            forgetting: HashMap::new(),
+            focus: Vec::new(),
+            spectator: false,
+            baselines: Baselines::new(),
+            priorities: Priorities::new(),
+            reliable: ReliableSender::new(),
+            incoming: ReliableReceiver::new(),
+            packer: Packer::new(MAX_PACKET_SIZE),
+            unpacker: Unpacker::new(),
+            queued: 0,
+            link: LinkStats::new(),
+            stats: NetStats::default(),
+        }
+    }
+
+    /// Queues a message for this client.
+    fn send(&mut self, msg: &[u8]) {
+        self.queued += msg.len();
+        self.link.message_sent(msg);
+        self.packer.push(msg);
+    }
+
+    /// Queues a message for this client, on the reliable channel.
+    fn send_reliable(&mut self, msg: &Message) {
+        let msg = self.reliable.push(msg);
+        self.send(&msg.bytes());
+    }
+
+    /// Tells the client to forget an entity, if it knows it.
+    fn forget(&mut self, id: u64) {
+        if self.known.remove(&id) {
+            self.baselines.forget(id);
+            self.priorities.forget(id);
+            self.forgetting.insert(id, self.reliable.next_seq());
+            self.send_reliable(&Message::EntityDelete(id));
+        }
+    }
+
+    /// Whether the client might not have forgotten an entity yet. Spawning
+    /// it again before then would race with the `EntityDelete`, which might
+    /// arrive last and leave it invisible.
+    fn still_forgetting(&mut self, id: u64) -> bool {
+        match self.forgetting.get(&id) {
+            Some(&seq) if !self.reliable.delivered(seq) => true,
+            Some(_) => {
+                self.forgetting.remove(&id);
+                false
             }
+            None => false,
         }
     }
 
-    /// Turn a message into bytes.
-    fn bytes(&self) -> Vec<u8> {
-        let mut msg: Vec<u8> = Vec::with_capacity(20);
-        self.to_bytes(&mut msg);
-        msg
+    /// Distance from a position to the closest of this client's ships.
+    fn distance(&self, pos: &Position) -> f32 {
+        if self.spectator {
+            return SPECTATOR_DISTANCE;
+        }
+        self.focus
+            .iter()
+            .map(|f| vec2_len([pos.pos[0] - f[0], pos.pos[1] - f[1]]))
+            .fold(f32::INFINITY, f32::min)
     }
-}
 
-/// Warns if a Result is an error.
-fn chk<T>(res: Result<T, io::Error>) {
-    match res {
-        Ok(_) => {}
-        Err(e) => warn!("Network error: {}", e),
+    /// Whether this client should know about an entity at a position.
+    fn interested(
+        &self,
+        id: u64,
+        pos: Option<&Position>,
+        config: &ServerConfig,
+    ) -> bool {
+        // Spectators watch the whole world
+        let pos = match pos {
+            Some(p) if !self.spectator => p,
+            _ => return true,
+        };
+        let mut radius = config.interest_radius;
+        if self.known.contains(&id) {
+            radius += config.interest_margin;
+        }
+        self.distance(pos) <= radius
     }
-}
 
-pub trait Server: Send + 'static {
-    type Address: Clone + Display + Eq + Send;
+    /// Queues the most important entity updates, until this tick's budget
+    /// is used up.
+    fn send_updates(&mut self, frame: u32, budget: usize) {
+        for update in self.priorities.take() {
+            if self.queued >= budget {
+                break;
+            }
+            // Until the client has a state of it, the spawn might have been
+            // lost, so send that again
+            let spawned = self.baselines.acknowledged(update.id);
+            let data = self.baselines.encode(
+                update.id,
+                frame,
+                update.state,
+                !spawned,
+            );
+            let msg = if spawned {
+                Message::EntityUpdate(update.id, frame, data)
+            } else {
+                Message::EntitySpawn(update.id, frame, update.kind, data)
+            };
+            self.send(&msg.bytes());
+            self.priorities.sent(update.id);
+        }
+    }
 
-    fn send(&self, msg: &[u8], addr: &Self::Address) -> io::Result<usize>;
-    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, Self::Address)>;
-}
+    /// Queues the reliable messages that weren't acknowledged in a while.
+    fn resend(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for msg in self.reliable.resend(now) {
+            self.send(&msg.bytes());
+        }
+    }
 
-pub trait Client: Send + 'static {
-    fn send(&self, msg: &[u8]) -> io::Result<usize>;
-    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    /// Tells the client it is being dropped, right away.
+    fn disconnect<S: Server<Address = A>>(
+        &mut self,
+        server: &S,
+        reason: DisconnectReason,
+    ) {
+        let msg = Message::Disconnect(reason).bytes();
+        for _ in 0..DISCONNECT_COPIES {
+            self.send(&msg);
+            self.flush(server);
+        }
+    }
+
+    /// Sends the queued messages, counting the bytes.
+    fn flush<S: Server<Address = A>>(&mut self, server: &S) {
+        self.queued = 0;
+        for packet in self.packer.take() {
+            self.link.packet_sent(packet.len());
+            chk(server.send(&packet, &self.address));
+        }
+    }
 }
 
-pub struct ConnectedClient<A: Eq> {
-    address: A,
-    client_id: u64,
-    ping: f32,
-    last_pong: SystemTime,
+/// A client that timed out, whose ships are kept for a while in case it
+/// reconnects.
+struct LostClient {
+    token: u64,
+    name: String,
+    spectator: bool,
+    since: SystemTime,
 }
 
 /// Network server system.
@@ -227,66 +411,215 @@ pub struct ConnectedClient<A: Eq> {
 /// Gets controls from clients and sends game updates.
 pub struct SysNetServer<S: Server> {
     server: S,
+    config: ServerConfig,
     frame: u32,
+    tick_length: f32,
+    world_size: f32,
     next_client: u64,
     clients: HashMap<u64, ConnectedClient<S::Address>>,
+    lost: HashMap<u64, LostClient>,
+    limiter: RateLimiter<S::Address>,
+    /// Limits chat messages, by client ID.
+    chat_limiter: RateLimiter<u64>,
+    /// Seconds since the `NetStats` resource was updated.
+    stats_elapsed: f32,
+    last_report: SystemTime,
 }
 
 impl<S: Server> SysNetServer<S> {
     /// Create a server, listening on the given port.
-    pub fn new(server: S) -> SysNetServer<S> {
+    pub fn new(server: S, config: ServerConfig) -> SysNetServer<S> {
         SysNetServer {
             server,
             frame: 0,
-            next_client: 1,
+            tick_length: 0.0,
+            world_size: 0.0,
+            next_client: (config.room as u64) << 32 | 1,
             clients: HashMap::new(),
+            lost: HashMap::new(),
+            limiter: RateLimiter::new(config.max_packet_rate),
+            chat_limiter: RateLimiter::with_burst(
+                config.chat_rate,
+                config.chat_burst,
+            ),
+            stats_elapsed: 0.0,
+            last_report: SystemTime::now(),
+            config,
+        }
+    }
+
+    /// Picks the protocol version to use with a client, disconnecting it if
+    /// there is none.
+    fn negotiate(&self, version: u16, src: &S::Address) -> Option<u16> {
+        let negotiated = negotiate_version(version);
+        if negotiated.is_none() {
+            warn!("Client {} has incompatible version {}", src, version);
+            chk(self.send(
+                &Message::Disconnect(DisconnectReason::VersionMismatch),
+                src,
+            ));
+        }
+        negotiated
+    }
+
+    /// Whether a client can be given another ship.
+    fn can_take_ship(
+        &self,
+        client_id: u64,
+        ctrl: &WriteStorage<ClientControlled>,
+    ) -> bool {
+        let ships = ctrl.join().filter(|c| c.client_id == client_id).count();
+        ships < self.config.max_ships_per_client
+    }
+
+    /// Whether a client ID and token go together, for a client that is
+    /// connected or can reconnect.
+    fn check_token(&self, client_id: u64, token: u64) -> bool {
+        match self.clients.get(&client_id) {
+            Some(client) => client.token == token,
+            None => {
+                self.lost.get(&client_id).is_some_and(|l| l.token == token)
+            }
+        }
+    }
+
+    /// Cleans up and checks text from a player, `None` if there is nothing
+    /// left to show.
+    fn filter(&self, text: &str, max_len: usize) -> Option<String> {
+        let text = sanitize(text, max_len);
+        let text = match self.config.chat_filter {
+            Some(ref filter) => filter.filter(&text)?,
+            None => text,
+        };
+        if text.is_empty() {
+            None
+        } else {
+            Some(text)
         }
     }
 
-    /// Sends a message.
+    /// Logs the network statistics, overall and for each client.
+    fn report(&self, stats: &NetStats) {
+        info!(
+            "{} clients: ping {:.0} ms, jitter {:.0} ms, loss {:.0}%, \
+             sent {:.0} B/s, received {:.0} B/s",
+            self.clients.len(),
+            stats.rtt * 1000.0,
+            stats.jitter * 1000.0,
+            stats.packet_loss * 100.0,
+            stats.bytes_out,
+            stats.bytes_in,
+        );
+        info!(
+            "Messages sent/s: {}",
+            NetStats::format_messages(&stats.messages_out)
+        );
+        info!(
+            "Messages received/s: {}",
+            NetStats::format_messages(&stats.messages_in)
+        );
+        for client in self.clients.values() {
+            info!(
+                "Client {}: ping {:.0} ms, loss {:.0}%, sent {:.0} B/s, \
+                 received {:.0} B/s",
+                client.client_id,
+                client.stats.rtt * 1000.0,
+                client.stats.packet_loss * 100.0,
+                client.stats.bytes_out,
+                client.stats.bytes_in,
+            );
+        }
+    }
+
+    /// Sends a message right away, to an address that might not be a
+    /// client.
     fn send(&self, msg: &Message, addr: &S::Address) -> io::Result<usize> {
-        self.server.send(&msg.bytes(), addr)
+        let mut packer = Packer::new(MAX_PACKET_SIZE);
+        packer.push(&msg.bytes());
+        let mut sent = 0;
+        for packet in packer.take() {
+            sent += self.server.send(&packet, addr)?;
+        }
+        Ok(sent)
     }
 }
 
 impl<'a, S: Server> System<'a> for SysNetServer<S> {
     type SystemData = (
+        Read<'a, DeltaTime>,
+        Read<'a, WorldConfig>,
         Read<'a, LazyUpdate>,
         Entities<'a>,
-        ReadStorage<'a, ClientControlled>,
+        WriteStorage<'a, ClientControlled>,
         WriteStorage<'a, Replicated>,
         WriteStorage<'a, Dirty>,
-        ReadStorage<'a, Delete>,
-        ReadStorage<'a, Position>,
+        WriteStorage<'a, Delete>,
+        WriteStorage<'a, Position>,
         ReadStorage<'a, Velocity>,
         WriteStorage<'a, Ship>,
         ReadStorage<'a, Asteroid>,
         ReadStorage<'a, Projectile>,
+        ReadStorage<'a, Blocky>,
         ReadStorage<'a, Effect>,
+        ReadStorage<'a, DetectCollision>,
+        WriteStorage<'a, PositionHistory>,
+        Write<'a, Rejected>,
+        Write<'a, NetStats>,
+        Write<'a, Admin>,
+        Write<'a, Chat>,
     );
 
     fn run(
         &mut self,
         (
+            dt,
+            world,
             lazy,
             entities,
-            ctrl,
+            mut ctrl,
             mut replicated,
             mut dirty,
-            delete,
-            position,
+            mut delete,
+            mut position,
             velocity,
             mut ship,
             asteroid,
             projectile,
+            blocky,
             effects,
+            collision,
+            mut history,
+            mut rejected,
+            mut stats,
+            mut admin,
+            mut chat,
         ): Self::SystemData,
     ) {
         self.frame = self.frame.wrapping_add(1);
 
+        // Tell clients about changes to the tick length
+        if dt.0 != self.tick_length {
+            self.tick_length = dt.0;
+            let msg = Message::TickLength(dt.0);
+            for client in self.clients.values_mut() {
+                client.send_reliable(&msg);
+            }
+        }
+        if world.size != self.world_size {
+            self.world_size = world.size;
+            let msg = Message::WorldSize(world.size);
+            for client in self.clients.values_mut() {
+                client.send_reliable(&msg);
+            }
+        }
+
         // Receive messages
         let mut messages = Vec::new();
-        let mut buffer = [0; 1024];
+        let mut received = Vec::new();
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let instant = Instant::now();
+        self.limiter.cleanup(instant);
+        self.chat_limiter.cleanup(instant);
         loop {
             let (len, src) = match self.server.recv(&mut buffer) {
                 Ok(r) => r,
@@ -297,224 +630,689 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
                     break;
                 }
             };
-            if len < 8 + 8 {
-                info!("Invalid message from {}", src);
+            if !self.limiter.allow(src.clone(), instant) {
+                rejected.rate_limited += 1;
+                continue;
+            }
+            if len < 16 {
+                info!("Invalid message from {}: no client ID", src);
+                rejected.malformed += 1;
                 continue;
             }
             let client_id = (&buffer[0..]).read_u64::<ORDER>().unwrap();
+            let token = (&buffer[8..]).read_u64::<ORDER>().unwrap();
+            if client_id != 0 && !self.check_token(client_id, token) {
+                info!("Invalid session token from {}", src);
+                rejected.bad_token += 1;
+                // Tell clients whose session expired, so they start over
+                if !self.clients.contains_key(&client_id)
+                    && !self.lost.contains_key(&client_id)
+                {
+                    let reason = DisconnectReason::UnknownSession;
+                    chk(self.send(&Message::Disconnect(reason), &src));
+                }
+                continue;
+            }
+            let packet = &buffer[16..len];
+            let unpacked = match self.clients.get_mut(&client_id) {
+                Some(client) => {
+                    client.link.packet_received(len);
+                    client.unpacker.unpack(packet)
+                }
+                // Not connected yet, nothing is big enough to be fragmented
+                None => Unpacker::new().unpack(packet),
+            };
+            match unpacked {
+                Ok(msgs) => received.extend(
+                    msgs.into_iter().map(|m| (client_id, src.clone(), m)),
+                ),
+                Err(e) => {
+                    info!("Invalid packet from {}: {}", src, e);
+                    rejected.malformed += 1;
+                }
+            }
+        }
+
+        // Take the messages clients sent on the reliable channel out of
+        // their envelope, and put them in order
+        let mut parsed = Vec::new();
+        for (client_id, src, msg) in received {
+            let mut client = self.clients.get_mut(&client_id);
+            if let Some(client) = &mut client {
+                client.link.message_received(&msg);
+            }
+            match (Message::parse(&msg), client) {
+                (Ok(Message::Reliable(seq, data)), Some(client)) => {
+                    for msg in client.incoming.receive(seq, data) {
+                        let msg = Message::parse(&msg);
+                        parsed.push((client_id, src.clone(), msg));
+                    }
+                    let ack = Message::ReliableAck(client.incoming.ack());
+                    client.send(&ack.bytes());
+                }
+                (msg, _) => parsed.push((client_id, src, msg)),
+            }
+        }
+
+        for (client_id, src, msg) in parsed {
+            match msg {
+                Ok(msg) => match msg {
+                    // Clients that timed out have to reconnect first
+                    _ if client_id != 0
+                        && !self.clients.contains_key(&client_id)
+                        && !matches!(msg, Message::Reconnect(_)) => {}
+                    Message::ClientHello(version, _, spectate, name) => {
+                        info!("Got ClientHello from {}", src);
+
+                        if self.clients.values().any(|c| c.address == src) {
+                            info!("Already have a client at {}", src);
+                            rejected.refused += 1;
+                            continue;
+                        }
+                        if self.clients.len() + self.lost.len()
+                            >= self.config.max_clients
+                        {
+                            warn!("Server full, turning away {}", src);
+                            rejected.refused += 1;
+                            chk(self.send(
+                                &Message::Disconnect(
+                                    DisconnectReason::ServerFull,
+                                ),
+                                &src,
+                            ));
+                            continue;
+                        }
 
-            if let Some(msg) = Message::parse(&buffer[8..len]) {
-                match msg {
-                    Message::ClientHello => {
-                        warn!("Got ClientHello from {}", src);
+                        let version = match self.negotiate(version, &src) {
+                            Some(v) => v,
+                            None => continue,
+                        };
 
                         // Create a client
                         let client_id = self.next_client;
                         self.next_client += 1;
-                        let now = SystemTime::now();
-                        self.clients.insert(
+                        let token = rand::random();
+                        let name = self
+                            .filter(&name, MAX_PLAYER_NAME)
+                            .unwrap_or_else(|| {
+                                format!("Player {}", client_id as u32)
+                            });
+                        let mut client = ConnectedClient::new(
+                            src.clone(),
                             client_id,
-                            ConnectedClient {
-                                address: src.clone(),
-                                client_id: client_id,
-                                ping: 0.0,
-                                last_pong: now,
-                            },
+                            token,
+                            name,
+                            SystemTime::now(),
                         );
+                        client.spectator = spectate;
 
                         // Send ServerHello
-                        chk(self.send(&Message::ServerHello(client_id), &src));
+                        let hello =
+                            Message::ServerHello(client_id, token, version);
+                        client.send_reliable(&hello);
+                        let tick = Message::TickLength(self.tick_length);
+                        client.send_reliable(&tick);
+                        let size = Message::WorldSize(self.world_size);
+                        client.send_reliable(&size);
 
                         // Create a ship for the new player
-                        let newship = Ship::create(&entities, &lazy);
-                        lazy.insert(
-                            newship,
-                            ClientControlled {
-                                client_id: client_id,
+                        if spectate {
+                            info!("Client {} is spectating", client_id);
+                        } else if self.can_take_ship(client_id, &ctrl) {
+                            let newship = Ship::create(&entities, &lazy);
+                            lazy.insert(
+                                newship,
+                                ClientControlled {
+                                    client_id,
+                                    last_input: 0,
+                                    view_tick: 0,
+                                },
+                            );
+                            let ship_id = replicated_id(newship);
+                            let control = Message::StartEntityControl(ship_id);
+                            client.send_reliable(&control);
+
+                            info!(
+                                "Created Ship {} for new client {}",
+                                ship_id, client_id
+                            );
+                        }
+                        self.clients.insert(client_id, client);
+                    }
+                    Message::Reconnect(version) => {
+                        let version = match self.negotiate(version, &src) {
+                            Some(v) => v,
+                            None => continue,
+                        };
+
+                        // The token was checked when the packet came in
+                        let previous = match self.clients.remove(&client_id) {
+                            Some(c) => (c.token, c.name, c.spectator),
+                            None => match self.lost.remove(&client_id) {
+                                Some(l) => (l.token, l.name, l.spectator),
+                                None => {
+                                    info!("Reconnect from unknown {}", src);
+                                    continue;
+                                }
                             },
-                        );
-                        let ship_id = (newship.gen().id() as u64) << 32
-                            | newship.id() as u64;
-                        chk(self.send(
-                            &Message::StartEntityControl(ship_id),
-                            &src,
-                        ));
-
-                        warn!(
-                            "Created Ship {} for new client {}",
-                            ship_id, client_id
-                        );
+                        };
+                        let (token, name, spectator) = previous;
+                        info!("Client {} reconnected from {}", client_id, src);
 
-                        // Send initial Ping message
-                        let d = now.duration_since(UNIX_EPOCH).unwrap();
-                        let d = time_encode(d);
-                        chk(self.send(&Message::Ping(d), &src));
+                        // Start over, giving it back its ships
+                        let mut client = ConnectedClient::new(
+                            src.clone(),
+                            client_id,
+                            token,
+                            name,
+                            SystemTime::now(),
+                        );
+                        client.spectator = spectator;
+                        let hello =
+                            Message::ServerHello(client_id, token, version);
+                        client.send_reliable(&hello);
+                        let tick = Message::TickLength(self.tick_length);
+                        client.send_reliable(&tick);
+                        let size = Message::WorldSize(self.world_size);
+                        client.send_reliable(&size);
+                        for (ent, ctrl) in (&*entities, &ctrl).join() {
+                            if ctrl.client_id == client_id {
+                                let id = replicated_id(ent);
+                                let control = Message::StartEntityControl(id);
+                                client.send_reliable(&control);
+                            }
+                        }
+                        self.clients.insert(client_id, client);
                     }
+                    // Answer right away, not to add to the round-trip time
                     Message::Ping(buf) => {
-                        chk(self.send(&Message::Pong(buf), &src))
-                    }
-                    Message::Pong(_) | Message::EntityUpdate(_, _) => {
-                        messages.push((client_id, msg))
+                        let pong = Message::Pong(buf);
+                        let sent = self.send(&pong, &src);
+                        if let (Some(client), Ok(len)) =
+                            (self.clients.get_mut(&client_id), &sent)
+                        {
+                            client.link.message_sent(&pong.bytes());
+                            client.link.packet_sent(*len);
+                        }
+                        chk(sent)
                     }
-                    Message::ServerHello(_)
+                    Message::Pong(_)
+                    | Message::ClientBye
+                    | Message::EntityUpdate(_, _, _)
+                    | Message::EntityAck(_)
+                    | Message::ReliableAck(_)
+                    | Message::ChatSend(_) => messages.push((client_id, msg)),
+                    Message::ServerHello(_, _, _)
+                    | Message::Disconnect(_)
+                    | Message::TickLength(_)
+                    | Message::WorldSize(_)
                     | Message::StartEntityControl(_)
-                    | Message::EntityDelete(_) => {
-                        info!("Invalid message from {}", src)
+                    | Message::EntitySpawn(_, _, _, _)
+                    | Message::EntityDelete(_)
+                    | Message::EntityBlocky(_, _)
+                    | Message::Effect(_, _)
+                    | Message::Reliable(_, _)
+                    | Message::DiscoveryRequest
+                    | Message::DiscoveryResponse(_)
+                    | Message::Chat(_, _) => {
+                        info!("Unexpected message from {}", src)
                     }
+                },
+                Err(e) => {
+                    info!("Invalid message from {}: {}", src, e);
+                    rejected.malformed += 1;
                 }
-            } else {
-                info!("Invalid message from {}", src);
-                continue;
             }
         }
 
-        // Handle Pong from clients
+        // Handle Pong and ClientBye from clients
+        let now = SystemTime::now();
+        let mut dropped = Vec::new();
+        let mut timed_out = Vec::new();
         for client in self.clients.values_mut() {
+            let mut bye = false;
             for &(ref client_id, ref msg) in &messages {
                 if client_id != &client.client_id {
                     continue;
                 }
 
                 if let Message::Pong(d) = *msg {
+                    // Only the low bits of the time were sent, compare to
+                    // the current time cut the same way
                     let d = time_decode(d);
-                    let now = SystemTime::now();
                     let now_d = now.duration_since(UNIX_EPOCH).unwrap();
+                    let now_d = time_decode(time_encode(now_d));
                     if let Some(d) = now_d.checked_sub(d) {
                         client.last_pong = now;
-                        client.ping = d.as_secs() as f32
-                            + d.subsec_nanos() as f32 / 0.000_000_001;
+                        client.link.pong_received(d.as_secs_f32());
                     }
+                } else if let Message::ClientBye = *msg {
+                    bye = true;
+                } else if let Message::EntityAck(ref acks) = *msg {
+                    for &(id, tick) in acks {
+                        client.baselines.acknowledge(id, tick);
+                    }
+                } else if let Message::ReliableAck(next) = *msg {
+                    client.reliable.acknowledge(next);
+                }
+            }
+
+            // Client said goodbye, it's gone for good
+            if bye {
+                info!("Client {} disconnected", client.client_id);
+                dropped.push(client.client_id);
+                continue;
+            }
+
+            // Give up on clients that let reliable messages pile up
+            if client.reliable.overflowed() {
+                warn!("Client {} is not keeping up", client.client_id);
+                client.disconnect(&self.server, DisconnectReason::TooSlow);
+                dropped.push(client.client_id);
+                continue;
+            }
+
+            // Drop old clients
+            match now.duration_since(client.last_pong) {
+                Ok(d) if d > self.config.client_timeout => {
+                    warn!("Client {} timed out", client.client_id);
+                    timed_out.push(client.client_id);
+                    continue;
+                }
+                _ => {}
+            }
+
+            // Ping clients regularly, so we know they are still there
+            match now.duration_since(client.last_ping) {
+                Ok(d) if d < PING_INTERVAL => {}
+                _ => {
+                    let d = now.duration_since(UNIX_EPOCH).unwrap();
+                    let ping = Message::Ping(time_encode(d)).bytes();
+                    client.send(&ping);
+                    client.link.ping_sent();
+                    client.last_ping = now;
                 }
             }
 
-            // TODO: Drop old clients
+            if let Some(s) = client.link.advance(dt.0, STATS_INTERVAL) {
+                client.stats = s;
+            }
+        }
+
+        // Keep the ships of clients that timed out for a while, idle, in
+        // case they come back
+        for client_id in timed_out {
+            if let Some(client) = self.clients.remove(&client_id) {
+                let lost = LostClient {
+                    token: client.token,
+                    name: client.name,
+                    spectator: client.spectator,
+                    since: now,
+                };
+                self.lost.insert(client_id, lost);
+            }
+            for (ship, ctrl) in (&mut ship, &ctrl).join() {
+                if ctrl.client_id == client_id {
+                    ship.want_fire = false;
+                    ship.want_thrust = [0.0, 0.0];
+                    ship.want_thrust_rot = 0.0;
+                }
+            }
+        }
+        let reconnect_timeout = self.config.reconnect_timeout;
+        self.lost.retain(|&client_id, lost| {
+            match now.duration_since(lost.since) {
+                Ok(d) if d > reconnect_timeout => {
+                    warn!("Client {} didn't come back", client_id);
+                    dropped.push(client_id);
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        // Pass chat messages on to everyone in the room, with the name of
+        // who said it
+        let mut said = Vec::new();
+        for &(client_id, ref msg) in &messages {
+            if let Message::ChatSend(ref text) = *msg {
+                let name = match self.clients.get(&client_id) {
+                    Some(client) => client.name.clone(),
+                    None => continue,
+                };
+                if text.trim().is_empty() {
+                    continue;
+                }
+                if !self.chat_limiter.allow(client_id, instant) {
+                    info!("Client {} is chatting too fast", client_id);
+                    rejected.chat_limited += 1;
+                    continue;
+                }
+                let max_len = self.config.max_chat_length;
+                if let Some(text) = self.filter(text, max_len) {
+                    said.push((name, text));
+                }
+            }
+        }
+        for text in chat.outgoing.drain(..) {
+            // The operator gets to say what they want
+            let text = sanitize(&text, self.config.max_chat_length);
+            if !text.is_empty() {
+                said.push(("Server".to_owned(), text));
+            }
+        }
+        for (sender, text) in said {
+            info!("Chat from {}: {}", sender, text);
+            let msg = Message::Chat(sender.clone(), text.clone());
+            for client in self.clients.values_mut() {
+                client.send_reliable(&msg);
+            }
+            chat.record(sender, text);
+        }
+
+        // Carry out the operator's commands
+        for command in admin.commands.drain(..) {
+            let (reason, kicked): (_, Vec<u64>) = match command {
+                AdminCommand::Kick(client_id) => {
+                    info!("Kicking client {}", client_id);
+                    (DisconnectReason::Kicked, vec![client_id])
+                }
+                AdminCommand::Shutdown => {
+                    info!("Shutting down, disconnecting all clients");
+                    let all = self.clients.keys().chain(self.lost.keys());
+                    (DisconnectReason::ShuttingDown, all.cloned().collect())
+                }
+            };
+            for client_id in kicked {
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.disconnect(&self.server, reason);
+                } else if self.lost.remove(&client_id).is_none() {
+                    warn!("No client {}", client_id);
+                    continue;
+                }
+                dropped.push(client_id);
+            }
+        }
+
+        // Delete the entities controlled by clients that left
+        for client_id in dropped {
+            self.clients.remove(&client_id);
+            for (ent, ctrl) in (&*entities, &ctrl).join() {
+                if ctrl.client_id == client_id {
+                    delete.insert(ent, Delete).unwrap();
+                }
+            }
+        }
+
+        // Show the clients to the operator
+        let mut ships = HashMap::new();
+        for ctrl in (&ctrl).join() {
+            *ships.entry(ctrl.client_id).or_insert(0) += 1;
+        }
+        admin.clients = self
+            .clients
+            .values()
+            .map(|client| ClientSummary {
+                client_id: client.client_id,
+                name: client.name.clone(),
+                address: client.address.to_string(),
+                rtt: client.link.rtt(),
+                ships: ships.get(&client.client_id).cloned().unwrap_or(0),
+                spectator: client.spectator,
+            })
+            .collect();
+        admin.clients.sort_by_key(|c| c.client_id);
+        admin.reconnecting = self.lost.len();
+
+        // Publish the network statistics, and log them once in a while
+        self.stats_elapsed += dt.0;
+        if self.stats_elapsed >= STATS_INTERVAL {
+            self.stats_elapsed = 0.0;
+            let links = self.clients.values().map(|c| &c.stats);
+            *stats = NetStats::combine(links);
+        }
+        match now.duration_since(self.last_report) {
+            Ok(d) if d >= REPORT_INTERVAL => {
+                self.report(&stats);
+                self.last_report = now;
+            }
+            _ => {}
+        }
+
+        // Find where the clients' ships are. Clients that lost theirs keep
+        // seeing the area where they were
+        let mut focus: HashMap<u64, Vec<_>> = HashMap::new();
+        for (ctrl, pos) in (&ctrl, &position).join() {
+            focus.entry(ctrl.client_id).or_default().push(pos.pos);
+        }
+        for client in self.clients.values_mut() {
+            if let Some(f) = focus.remove(&client.client_id) {
+                client.focus = f;
+            }
+        }
+
+        // Check the start of new shots against the world as the shooter
+        // saw it
+        let mut rewound = Vec::new();
+        {
+            let targets: Vec<_> = (&*entities, &history, &position, &blocky)
+                .join()
+                .map(|(entity, history, pos, blocky)| Target {
+                    entity,
+                    history,
+                    pos,
+                    blocky,
+                })
+                .collect();
+            for (ent, proj, repli, pos, vel, col) in (
+                &*entities,
+                &projectile,
+                &replicated,
+                &position,
+                &velocity,
+                &collision,
+            ).join()
+            {
+                // Projectiles get their ID once they've been seen here
+                if repli.id != 0 {
+                    continue;
+                }
+                let view_tick = match ctrl.get(proj.shooter) {
+                    Some(c) => c.view_tick,
+                    None => continue,
+                };
+                let rewind = self.frame.wrapping_sub(view_tick) as i32;
+                if view_tick == 0 || rewind <= 0 {
+                    continue;
+                }
+                let new_pos = rewind_projectile(
+                    pos,
+                    vec2_scale(vel.vel, dt.0),
+                    col,
+                    proj.shooter,
+                    self.frame,
+                    (rewind as u32).min(MAX_REWIND),
+                    &targets,
+                );
+                rewound.push((ent, new_pos));
+            }
+        }
+        for (ent, pos) in rewound {
+            position.insert(ent, pos).unwrap();
+        }
+
+        // Remember where things were, for the next shots
+        for (ent, pos, _) in (&*entities, &position, &blocky).join() {
+            if history.get(ent).is_none() {
+                history.insert(ent, PositionHistory::new()).unwrap();
+            }
+            history.get_mut(ent).unwrap().record(self.frame, pos);
         }
 
         // Go over entities, send updates
-        for (ent, mut repli) in (&*entities, &mut replicated).join() {
+        for (ent, repli) in (&*entities, &mut replicated).join() {
             // Assign replicated object ID
             if repli.id == 0 {
-                repli.id = (ent.gen().id() as u64) << 32 | ent.id() as u64;
+                repli.id = replicated_id(ent);
             }
 
             // Deleted?
             if delete.get(ent).is_some() {
-                let message = Message::EntityDelete(repli.id).bytes();
                 for client in self.clients.values_mut() {
-                    chk(self.server.send(&message, &client.address));
+                    client.forget(repli.id);
+                    client.forgetting.remove(&repli.id);
                 }
                 entities.delete(ent).unwrap();
                 continue;
             }
 
-            // Send an update if dirty, or if it hasn't been updated in a while
-            if dirty.get(ent).is_none()
-                && self.frame.wrapping_sub(repli.last_update) < 200
-            {
-                continue;
-            }
-
-            // Send entity update
-            let mut data;
-            if let Some(ship) = ship.get(ent) {
-                let pos = position.get(ent).unwrap();
-                let vel = velocity.get(ent).unwrap();
-                data = Vec::with_capacity(56);
-                write_float(&mut data, pos.pos[0]);
-                write_float(&mut data, pos.pos[1]);
-                write_float(&mut data, pos.rot);
-                write_float(&mut data, vel.vel[0]);
-                write_float(&mut data, vel.vel[1]);
-                write_float(&mut data, vel.rot);
-                write_float(&mut data, ship.want_thrust[0]);
-                write_float(&mut data, ship.want_thrust[1]);
-                write_float(&mut data, ship.want_thrust_rot);
-                write_float(&mut data, ship.want_target[0]);
-                write_float(&mut data, ship.want_target[1]);
-                write_float(&mut data, ship.thrust[0]);
-                write_float(&mut data, ship.thrust[1]);
-                write_float(&mut data, ship.thrust_rot);
-                assert_eq!(data.len(), 56);
+            let kind = if ship.get(ent).is_some() {
+                EntityKind::Ship
             } else if asteroid.get(ent).is_some() {
-                let pos = position.get(ent).unwrap();
-                let vel = velocity.get(ent).unwrap();
-                data = Vec::with_capacity(24);
-                write_float(&mut data, pos.pos[0]);
-                write_float(&mut data, pos.pos[1]);
-                write_float(&mut data, pos.rot);
-                write_float(&mut data, vel.vel[0]);
-                write_float(&mut data, vel.vel[1]);
-                write_float(&mut data, vel.rot);
-                assert_eq!(data.len(), 24);
-            } else if let Some(proj) = projectile.get(ent) {
-                let pos = position.get(ent).unwrap();
-                let vel = velocity.get(ent).unwrap();
-                data = Vec::with_capacity(25);
-                write_float(&mut data, pos.pos[0]);
-                write_float(&mut data, pos.pos[1]);
-                write_float(&mut data, pos.rot);
-                write_float(&mut data, vel.vel[0]);
-                write_float(&mut data, vel.vel[1]);
-                write_float(&mut data, vel.rot);
-                let kind = match proj.kind {
-                    ProjectileType::Plasma => 1,
-                    ProjectileType::Rail => 2,
-                };
-                assert_eq!(data.write(&[0u8]).unwrap(), kind);
-                assert_eq!(data.len(), 25);
+                EntityKind::Asteroid
+            } else if blocky.get(ent).is_some() {
+                // Pieces that broke off a ship
+                EntityKind::Debris
+            } else if projectile.get(ent).is_some() {
+                EntityKind::Projectile
             } else {
                 panic!("Need to send update for unknown entity!");
-            }
-            let update = Message::EntityUpdate(repli.id, data).bytes();
+            };
+            let is_dirty = dirty.get(ent).is_some();
+            let pos = position.get(ent);
+            let speed = velocity.get(ent).map_or(0.0, |v| vec2_len(v.vel));
+            let mut state = EntityState {
+                position: pos.cloned(),
+                velocity: velocity.get(ent).cloned(),
+                ship: ship.get(ent).cloned(),
+                projectile: projectile
+                    .get(ent)
+                    .map(|p| (p.kind, replicated_id(p.shooter))),
+                input_ack: None,
+            };
+            let shared = state.quantize();
+
+            // The controlling client also gets told which of its inputs made
+            // it into this state, so it can predict from there
+            let owner = ctrl.get(ent).map(|ctrl| {
+                state.input_ack = Some(ctrl.last_input);
+                (ctrl.client_id, state.quantize())
+            });
+
+            // Send the structure along with spawns, and if it changed. It
+            // is only written when some client needs it
+            let blk = blocky.get(ent).map(|blk| {
+                let changed = repli.blocky_revision != Some(blk.revision);
+                repli.blocky_revision = Some(blk.revision);
+                (blk, changed)
+            });
+            let mut structure = None;
+
             for client in self.clients.values_mut() {
-                chk(self.server.send(&update, &client.address));
+                // Introduce it to clients it came close to, and tell the
+                // ones it went away from to forget it
+                if !client.interested(repli.id, pos, &self.config) {
+                    client.forget(repli.id);
+                    continue;
+                }
+                if client.still_forgetting(repli.id) {
+                    continue;
+                }
+                let (quantized, owned) = match owner {
+                    Some((id, ref quantized)) if id == client.client_id => {
+                        (quantized, true)
+                    }
+                    _ => (&shared, false),
+                };
+                let new = client.known.insert(repli.id);
+                if new {
+                    // Spawns don't use a baseline, they might be the first
+                    // state the client gets
+                    let data = client.baselines.encode(
+                        repli.id,
+                        self.frame,
+                        quantized.clone(),
+                        true,
+                    );
+                    let spawn =
+                        Message::EntitySpawn(repli.id, self.frame, kind, data);
+                    client.send(&spawn.bytes());
+                    client.priorities.sent(repli.id);
+                } else {
+                    // Updates wait for their turn
+                    let distance = pos.map_or(0.0, |p| client.distance(p));
+                    let update = Update {
+                        id: repli.id,
+                        kind,
+                        state: quantized.clone(),
+                    };
+                    client.priorities.push(
+                        update,
+                        priority(kind, distance, speed, is_dirty, owned),
+                    );
+                }
+                if let Some((blk, changed)) = blk {
+                    if new || changed {
+                        let msg = structure.get_or_insert_with(|| {
+                            let mut data = Vec::new();
+                            write_blocky(&mut data, blk);
+                            Message::EntityBlocky(repli.id, data)
+                        });
+                        client.send_reliable(msg);
+                    }
+                }
             }
-
-            repli.last_update = self.frame;
         }
 
-        // Send particle effects
-        for (_effect, _) in (&effects, &dirty).join() {
-            // TODO: Send particle effects
+        // Send particle effects, once
+        for (ent, effect, pos, _) in
+            (&*entities, &effects, &position, &dirty).join()
+        {
+            let msg = Message::Effect(effect.effect.clone(), pos.pos).bytes();
+            for client in self.clients.values_mut() {
+                if client.interested(0, Some(pos), &self.config) {
+                    client.send(&msg);
+                }
+            }
+            entities.delete(ent).unwrap();
         }
 
         dirty.clear();
 
+        // Send everything queued for this frame, and the updates that fit
+        for client in self.clients.values_mut() {
+            // Messages waiting to be sent again come first, and count
+            // against the budget
+            client.resend();
+            client.send_updates(self.frame, self.config.update_budget);
+            client.flush(&self.server);
+        }
+
         // Handle messages
         for (ent, ship, repli, ctrl) in
-            (&*entities, &mut ship, &mut replicated, &ctrl).join()
+            (&*entities, &mut ship, &mut replicated, &mut ctrl).join()
         {
             for &(ref client_id, ref msg) in &messages {
-                if let Message::EntityUpdate(id, ref data) = *msg {
+                if let Message::EntityUpdate(id, seq, ref data) = *msg {
                     if repli.id == id && client_id == &ctrl.client_id {
-                        repli.last_update = self.frame;
+                        // Inputs that got overtaken are out of date
+                        if !seq_newer(seq, ctrl.last_input) {
+                            continue;
+                        }
 
                         // Update entity from message data
-                        if data.len() != 9 {
-                            info!("Invalid ship control update");
-                            continue;
+                        match Controls::read(data) {
+                            Ok(controls) => {
+                                controls.apply(ship);
+                                ctrl.last_input = seq;
+                                ctrl.view_tick = controls.view_tick;
+                                dirty.insert(ent, Dirty).unwrap();
+                            }
+                            Err(e) => {
+                                info!("Invalid ship control update: {}", e);
+                                rejected.invalid_controls += 1;
+                            }
                         }
-                        let flags = data[0];
-                        ship.want_fire = flags & 0x01 == 0x01;
-                        ship.want_thrust[0] = match flags & 0x06 {
-                            0x02 => 1.0,
-                            0x04 => -1.0,
-                            _ => 0.0,
-                        };
-                        ship.want_thrust[1] = if flags & 0x08 == 0x08 {
-                            1.0
-                        } else {
-                            0.0
-                        };
-                        ship.want_thrust_rot = match flags & 0x30 {
-                            0x10 => 1.0,
-                            0x20 => -1.0,
-                            _ => 0.0,
-                        };
-                        let mut data = Cursor::new(&data[1..]);
-                        ship.want_target[0] = read_float(&mut data);
-                        ship.want_target[1] = read_float(&mut data);
-                        dirty.insert(ent, Dirty).unwrap();
                     }
                 }
             }
@@ -522,73 +1320,203 @@ impl<'a, S: Server> System<'a> for SysNetServer<S> {
     }
 }
 
+/// Gets the entity, tick and state carried by `EntitySpawn` and
+/// `EntityUpdate`.
+fn entity_state(msg: &Message) -> Option<(u64, u32, &[u8])> {
+    match *msg {
+        Message::EntitySpawn(id, tick, _, ref data)
+        | Message::EntityUpdate(id, tick, ref data) => Some((id, tick, data)),
+        _ => None,
+    }
+}
+
 /// Network client system.
 ///
-/// Sends controls to server and gets game updates.
+/// Sends controls to server and gets game updates. Dropping it tells the
+/// server that we are leaving.
 pub struct SysNetClient<C: Client> {
     client: C,
+    config: ClientConfig,
     client_id: u64,
-    last_pong: SystemTime,
-    ping: f32,
+    token: u64,
+    protocol_version: u16,
+    /// Seconds since we last heard from the server.
+    silence: f32,
+    reconnecting: bool,
+    /// The server closed the connection, there is nothing more to send.
+    disconnected: bool,
+    /// Time since we started, counted in ticks since there is no clock in
+    /// the browser.
+    elapsed: Duration,
+    last_ping: Duration,
+    last_hello: Duration,
+    link: LinkStats,
     controlled_entities: HashSet<u64>,
+    pending_blocky: HashMap<u64, Blocky>,
+    clock: PlaybackClock,
+    prediction: Prediction,
+    history: History,
+    reliable: ReliableReceiver,
+    /// Messages we send on the reliable channel.
+    outgoing: ReliableSender,
+    packer: Packer,
+    unpacker: Unpacker,
 }
 
 impl<C: Client> SysNetClient<C> {
     /// Create a client, connected to the specified server.
-    pub fn new(client: C) -> SysNetClient<C> {
-        let client = SysNetClient {
+    pub fn new(client: C, config: ClientConfig) -> SysNetClient<C> {
+        let mut client = SysNetClient {
             client,
+            config,
             client_id: 0,
-            last_pong: SystemTime::now(),
-            ping: 0.0,
+            token: 0,
+            protocol_version: 0,
+            silence: 0.0,
+            reconnecting: false,
+            disconnected: false,
+            elapsed: Duration::from_secs(0),
+            last_ping: Duration::from_secs(0),
+            last_hello: Duration::from_secs(0),
+            link: LinkStats::new(),
             controlled_entities: HashSet::new(),
+            pending_blocky: HashMap::new(),
+            clock: PlaybackClock::new(),
+            prediction: Prediction::new(),
+            history: History::new(),
+            reliable: ReliableReceiver::new(),
+            outgoing: ReliableSender::new(),
+            // Room for our client ID and token in front of the packets
+            packer: Packer::new(MAX_PACKET_SIZE - 16),
+            unpacker: Unpacker::new(),
         };
-        client.send(&Message::ClientHello).unwrap();
+        client.send(&client.hello());
+        client.flush();
         client
     }
 
-    /// Sends a message
-    fn send(&self, msg: &Message) -> io::Result<usize> {
-        let mut bytes = Vec::new();
-        bytes
-            .write_u64::<ORDER>(self.client_id)
-            .unwrap();
-        msg.to_bytes(&mut bytes);
-        self.client
-            .send(&bytes)
+    /// Introduces us to the server.
+    fn hello(&self) -> Message {
+        Message::ClientHello(
+            PROTOCOL_VERSION,
+            self.config.room,
+            self.config.spectate,
+            self.config.name.clone(),
+        )
+    }
+
+    /// Queues a message
+    fn send(&mut self, msg: &Message) {
+        let bytes = msg.bytes();
+        self.link.message_sent(&bytes);
+        self.packer.push(&bytes);
+    }
+
+    /// Starts over, the server will send everything again.
+    fn forget(
+        &mut self,
+        entities: &EntitiesRes,
+        replicated: &ReadStorage<Replicated>,
+    ) {
+        for (ent, _) in (entities, replicated).join() {
+            entities.delete(ent).unwrap();
+        }
+        self.controlled_entities.clear();
+        self.pending_blocky.clear();
+        self.clock = PlaybackClock::new();
+        self.prediction = Prediction::new();
+        self.history = History::new();
+        self.reliable = ReliableReceiver::new();
+        self.outgoing = ReliableSender::new();
+        self.unpacker = Unpacker::new();
+    }
+
+    /// Queues a message, on the reliable channel
+    fn send_reliable(&mut self, msg: &Message) {
+        let msg = self.outgoing.push(msg);
+        self.send(&msg);
+    }
+
+    /// Sends the queued messages
+    fn flush(&mut self) {
+        for msg in self.outgoing.resend(self.elapsed) {
+            self.send(&msg);
+        }
+        for packet in self.packer.take() {
+            let mut bytes = Vec::with_capacity(16 + packet.len());
+            bytes.write_u64::<ORDER>(self.client_id).unwrap();
+            bytes.write_u64::<ORDER>(self.token).unwrap();
+            bytes.extend_from_slice(&packet);
+            self.link.packet_sent(bytes.len());
+            chk(self.client.send(&bytes));
+        }
+    }
+}
+
+impl<C: Client> Drop for SysNetClient<C> {
+    fn drop(&mut self) {
+        if self.client_id != 0 {
+            self.send(&Message::ClientBye);
+            self.flush();
+        }
     }
 }
 
 impl<'a, C: Client> System<'a> for SysNetClient<C> {
     type SystemData = (
+        Read<'a, DeltaTime>,
         Entities<'a>,
         Read<'a, LazyUpdate>,
         ReadStorage<'a, Replicated>,
         WriteStorage<'a, Dirty>,
+        ReadStorage<'a, LocalControl>,
         WriteStorage<'a, Position>,
         WriteStorage<'a, Velocity>,
+        WriteStorage<'a, Snapshots>,
         WriteStorage<'a, Ship>,
-        ReadStorage<'a, Asteroid>,
-        ReadStorage<'a, Projectile>,
+        WriteStorage<'a, Blocky>,
+        Write<'a, NetStats>,
+        Write<'a, WorldConfig>,
+        Write<'a, Chat>,
     );
 
     fn run(
         &mut self,
         (
+            dt,
             entities,
             lazy,
             replicated,
             mut dirty,
+            local,
             mut position,
             mut velocity,
+            mut snapshots,
             mut ship,
-            asteroid,
-            projectile,
+            mut blocky,
+            mut stats,
+            mut world,
+            mut chat,
         ): Self::SystemData,
     ) {
+        self.elapsed += Duration::from_secs_f32(dt.0);
+
+        // Send the controls of our ships, and keep them, to be replayed on
+        // top of the states from the server until it has applied them
+        let seq = self.prediction.next_seq();
+        let view_tick = self.clock.playback_tick().unwrap_or(0);
+        for (ship, repli, _) in (&ship, &replicated, &local).join() {
+            let controls = Controls::from_ship(ship, view_tick);
+            let mut data = Vec::with_capacity(13);
+            controls.write(&mut data);
+            self.send(&Message::EntityUpdate(repli.id, seq, data));
+            self.prediction.record(seq, repli.id, dt.0, controls);
+        }
+
         // Receive messages
         let mut messages = Vec::new();
-        let mut buffer = [0; 1024];
+        let mut received = Vec::new();
+        let mut buffer = [0; MAX_PACKET_SIZE];
         loop {
             let len = match self.client.recv(&mut buffer) {
                 Ok(r) => r,
@@ -599,47 +1527,180 @@ impl<'a, C: Client> System<'a> for SysNetClient<C> {
                     break;
                 }
             };
+            self.link.packet_received(len);
+            match self.unpacker.unpack(&buffer[..len]) {
+                Ok(msgs) => received.extend(msgs),
+                Err(e) => warn!("Invalid packet: {}", e),
+            }
+            self.silence = 0.0;
+        }
 
-            if let Some(msg) = Message::parse(&buffer[..len]) {
-                match msg {
-                    Message::ServerHello(client_id) => {
-                        warn!("Got ServerHello, our ID is {}", client_id);
-                        self.client_id = client_id;
-                    }
-                    Message::Ping(buf) => chk(self.send(&Message::Pong(buf))),
-                    Message::Pong(d) => {
-                        let d = time_decode(d);
-                        let now = SystemTime::now();
-                        let now_d = now.duration_since(UNIX_EPOCH).unwrap();
-                        if let Some(d) = now_d.checked_sub(d) {
-                            self.last_pong = now;
-                            self.ping = d.as_secs() as f32
-                                + d.subsec_nanos() as f32 / 0.000_000_001;
+        // Server went quiet, try to get our ships back, possibly from a new
+        // address
+        self.silence += dt.0;
+        let reconnect_after = self.config.reconnect_after.as_secs_f32();
+        if self.client_id != 0 && self.silence >= reconnect_after {
+            if !self.reconnecting {
+                warn!("Lost connection to server, reconnecting");
+                self.reconnecting = true;
+                self.forget(&entities, &replicated);
+            }
+            self.silence = 0.0;
+            self.send(&Message::Reconnect(PROTOCOL_VERSION));
+            self.flush();
+        }
+
+        // Ping the server regularly, to measure the round-trip time
+        if self.client_id != 0
+            && !self.reconnecting
+            && self.elapsed - self.last_ping >= PING_INTERVAL
+        {
+            self.send(&Message::Ping(time_encode(self.elapsed)));
+            self.link.ping_sent();
+            self.last_ping = self.elapsed;
+        }
+
+        // Take the messages on the reliable channel out of their envelope,
+        // and put them in order
+        let mut parsed = Vec::new();
+        let mut got_reliable = false;
+        for msg in received {
+            self.link.message_received(&msg);
+            match Message::parse(&msg) {
+                Ok(Message::Reliable(seq, data)) => {
+                    got_reliable = true;
+                    for msg in self.reliable.receive(seq, data) {
+                        match Message::parse(&msg) {
+                            Ok(msg) => parsed.push(msg),
+                            Err(e) => warn!("Invalid message: {}", e),
                         }
                     }
-                    Message::StartEntityControl(id) => {
-                        self.controlled_entities.insert(id);
+                }
+                Ok(msg) => parsed.push(msg),
+                Err(e) => warn!("Invalid message: {}", e),
+            }
+        }
+        if got_reliable {
+            self.send(&Message::ReliableAck(self.reliable.ack()));
+        }
+
+        let mut new_control = Vec::new();
+        for msg in parsed {
+            match msg {
+                Message::ServerHello(client_id, token, version) => {
+                    if version < MIN_PROTOCOL_VERSION
+                        || version > PROTOCOL_VERSION
+                    {
+                        warn!("Server picked unsupported version {}", version);
+                        continue;
                     }
-                    Message::EntityUpdate(_, _) | Message::EntityDelete(_) => {
-                        messages.push((msg, false))
+                    info!(
+                        "Got ServerHello, our ID is {}, protocol version {}",
+                        client_id, version
+                    );
+                    self.client_id = client_id;
+                    self.token = token;
+                    self.protocol_version = version;
+                    if self.reconnecting {
+                        info!("Reconnected");
+                        self.reconnecting = false;
                     }
-                    Message::ClientHello => warn!("Invalid message"),
                 }
-            } else {
-                warn!("Invalid message");
+                // Took too long to come back, join again as someone new
+                Message::Disconnect(DisconnectReason::UnknownSession)
+                    if self.reconnecting =>
+                {
+                    warn!("Server forgot about us, joining again");
+                    self.forget(&entities, &replicated);
+                    self.client_id = 0;
+                    self.token = 0;
+                    self.reconnecting = false;
+                }
+                // Answer to packets from a session we already gave up on
+                Message::Disconnect(DisconnectReason::UnknownSession) => {}
+                // Sent a few times
+                Message::Disconnect(_) if self.disconnected => {}
+                Message::Disconnect(reason) => {
+                    warn!("Disconnected by server: {}", reason);
+                    self.client_id = 0;
+                    self.disconnected = true;
+                }
+                Message::Ping(buf) => self.send(&Message::Pong(buf)),
+                Message::Pong(d) => {
+                    let d = self.elapsed.checked_sub(time_decode(d));
+                    if let Some(d) = d {
+                        self.link.pong_received(d.as_secs_f32());
+                    }
+                }
+                Message::TickLength(length) => {
+                    self.clock.tick_length = length;
+                }
+                Message::WorldSize(size) => world.size = size,
+                Message::ReliableAck(next) => self.outgoing.acknowledge(next),
+                Message::Chat(sender, text) => {
+                    chat.record(sender, text)
+                }
+                Message::StartEntityControl(id) => {
+                    self.controlled_entities.insert(id);
+                    new_control.push(id);
+                }
+                Message::Effect(effect, pos) => {
+                    // Materialize particle effect
+                    let entity = entities.create();
+                    lazy.insert(entity, Position { pos, rot: 0.0 });
+                    lazy.insert(
+                        entity,
+                        Effect {
+                            effect,
+                            lifetime: -1.0,
+                        },
+                    );
+                }
+                Message::EntitySpawn(_, _, _, _)
+                | Message::EntityUpdate(_, _, _)
+                | Message::EntityDelete(_)
+                | Message::EntityBlocky(_, _) => messages.push((msg, false)),
+                Message::ClientHello(_, _, _, _)
+                | Message::Reconnect(_)
+                | Message::ClientBye
+                | Message::EntityAck(_)
+                | Message::Reliable(_, _)
+                | Message::DiscoveryRequest
+                | Message::DiscoveryResponse(_)
+                | Message::ChatSend(_) => warn!("Unexpected message"),
+            }
+        }
+
+        // Say hello again until the server answers, in case it got lost
+        if self.client_id == 0
+            && !self.disconnected
+            && self.elapsed - self.last_hello >= HELLO_INTERVAL
+        {
+            self.send(&self.hello());
+            self.last_hello = self.elapsed;
+        }
+
+        // Take over ships that were created before we knew they were ours
+        for (ent, repli, _) in (&*entities, &replicated, &ship).join() {
+            if new_control.contains(&repli.id) {
+                info!("Taking control of ship {}", repli.id);
+                lazy.insert(ent, LocalControl);
+                lazy.remove::<Snapshots>(ent);
             }
         }
 
         // Update entities from messages
-        for (ent, repli, mut pos, mut vel) in (
+        let mut acks = Vec::new();
+        for (ent, repli, pos, vel, mut buffer) in (
             &*entities,
             &replicated,
             &mut position,
             &mut velocity,
+            (&mut snapshots).maybe(),
         ).join()
         {
             for &mut (ref msg, ref mut handled) in &mut messages {
-                if let Message::EntityUpdate(id, ref data) = *msg {
+                if let Some((id, tick, data)) = entity_state(msg) {
                     if id != repli.id {
                         continue;
                     }
@@ -647,46 +1708,72 @@ impl<'a, C: Client> System<'a> for SysNetClient<C> {
                     *handled = true;
 
                     // Update entity from message
-                    if let Some(ship) = ship.get_mut(ent) {
-                        assert_eq!(data.len(), 56);
-                        let mut data = Cursor::new(data);
-                        pos.pos[0] = read_float(&mut data);
-                        pos.pos[1] = read_float(&mut data);
-                        pos.rot = read_float(&mut data);
-                        vel.vel[0] = read_float(&mut data);
-                        vel.vel[1] = read_float(&mut data);
-                        vel.rot = read_float(&mut data);
-                        ship.want_thrust[0] = read_float(&mut data);
-                        ship.want_thrust[1] = read_float(&mut data);
-                        ship.want_thrust_rot = read_float(&mut data);
-                        ship.want_target[0] = read_float(&mut data);
-                        ship.want_target[1] = read_float(&mut data);
-                        ship.thrust[0] = read_float(&mut data);
-                        ship.thrust[1] = read_float(&mut data);
-                        ship.thrust_rot = read_float(&mut data);
-                        assert_eq!(data.position(), 56);
-                    } else if asteroid.get(ent).is_some() {
-                        assert_eq!(data.len(), 24);
-                        let mut data = Cursor::new(data);
-                        pos.pos[0] = read_float(&mut data);
-                        pos.pos[1] = read_float(&mut data);
-                        pos.rot = read_float(&mut data);
-                        vel.vel[0] = read_float(&mut data);
-                        vel.vel[1] = read_float(&mut data);
-                        vel.rot = read_float(&mut data);
-                        assert_eq!(data.position(), 24);
-                    } else if projectile.get(ent).is_some() {
-                        assert_eq!(data.len(), 25);
-                        let mut data = Cursor::new(data);
-                        pos.pos[0] = read_float(&mut data);
-                        pos.pos[1] = read_float(&mut data);
-                        pos.rot = read_float(&mut data);
-                        vel.vel[0] = read_float(&mut data);
-                        vel.vel[1] = read_float(&mut data);
-                        vel.rot = read_float(&mut data);
-                        assert_eq!(data.position(), 24);
-                    } else {
-                        panic!("Got update for unknown entity!");
+                    let state = match self.history.decode(id, tick, data) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("Invalid entity state: {}", e);
+                            continue;
+                        }
+                    };
+                    acks.push((id, tick));
+                    if let Some(seq) = state.input_ack {
+                        if !self.prediction.acknowledge(id, seq) {
+                            continue;
+                        }
+                    }
+                    let time = self.clock.tick_time(tick);
+                    match (state.position, state.velocity, buffer.as_mut()) {
+                        (Some(new_pos), Some(new_vel), Some(buffer)) => {
+                            // Keep it, it will be shown after a delay
+                            buffer.insert(time, new_pos, new_vel);
+                        }
+                        (new_pos, new_vel, _) => {
+                            if let Some(new_pos) = new_pos {
+                                *pos = new_pos;
+                            }
+                            if let Some(new_vel) = new_vel {
+                                *vel = new_vel;
+                            }
+                        }
+                    }
+                    match state.ship {
+                        Some(new_ship) => {
+                            if let Some(ship) = ship.get_mut(ent) {
+                                // Fire is the only control not replicated
+                                let want_fire = ship.want_fire;
+                                *ship = new_ship;
+                                ship.want_fire = want_fire;
+                            }
+                        }
+                        None => {
+                            if ship.get(ent).is_some() {
+                                // No longer a ship, its cockpit got destroyed
+                                ship.remove(ent);
+                            }
+                        }
+                    }
+
+                    // Replay our inputs the server didn't have yet
+                    if state.input_ack.is_some() {
+                        if let (Some(ship), Some(blk)) =
+                            (ship.get_mut(ent), blocky.get(ent))
+                        {
+                            self.prediction.replay(id, pos, vel, ship, blk);
+                        }
+                    }
+                } else if let Message::EntityBlocky(id, ref data) = *msg {
+                    if id != repli.id {
+                        continue;
+                    }
+
+                    *handled = true;
+
+                    // Replace structure
+                    match read_blocky(data) {
+                        Ok(blk) => {
+                            blocky.insert(ent, blk).unwrap();
+                        }
+                        Err(e) => warn!("Invalid EntityBlocky: {}", e),
                     }
                 } else if let Message::EntityDelete(id) = *msg {
                     if id != repli.id {
@@ -694,156 +1781,156 @@ impl<'a, C: Client> System<'a> for SysNetClient<C> {
                     }
 
                     // Delete entity
-                    entities.delete(ent).unwrap();
+                    if entities.is_alive(ent) {
+                        entities.delete(ent).unwrap();
+                    }
+                    self.prediction.forget(id);
+                    self.history.forget(id);
                 }
             }
         }
 
         // Create new entities
+        let mut created = HashMap::new();
         for &(ref msg, handled) in &messages {
             if handled {
                 continue;
             }
-            if let Message::EntityUpdate(id, ref data) = *msg {
-                if data.len() == 56 {
-                    let mut data = Cursor::new(data);
-                    let pos = Position {
-                        pos: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let vel = Velocity {
-                        vel: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let ship = Ship {
-                        want_fire: false,
-                        want_thrust: [
-                            read_float(&mut data),
-                            read_float(&mut data),
-                        ],
-                        want_thrust_rot: read_float(&mut data),
-                        want_target: [
-                            read_float(&mut data),
-                            read_float(&mut data),
-                        ],
-                        thrust: [read_float(&mut data), read_float(&mut data)],
-                        thrust_rot: read_float(&mut data),
-                    };
-                    assert_eq!(data.position(), 56);
-
-                    let entity = entities.create();
+            if let Message::EntitySpawn(id, tick, kind, ref data) = *msg {
+                if created.contains_key(&id) {
+                    continue;
+                }
+                let state = match self.history.decode(id, tick, data) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("Invalid EntitySpawn: {}", e);
+                        continue;
+                    }
+                };
+                acks.push((id, tick));
+                let required = kind.required_components();
+                if state.components() & required != required {
+                    warn!("EntitySpawn for {:?} is missing components", kind);
+                    continue;
+                }
+                let entity = entities.create();
+                let local = state.ship.is_some()
+                    && self.controlled_entities.contains(&id);
+                if let (Some(pos), Some(vel), false) =
+                    (&state.position, &state.velocity, local)
+                {
+                    // Interpolate, unless we predict it ourselves
+                    let mut buffer = Snapshots::new();
+                    let time = self.clock.tick_time(tick);
+                    buffer.insert(time, pos.clone(), vel.clone());
+                    lazy.insert(entity, buffer);
+                }
+                if let Some(pos) = state.position {
                     lazy.insert(entity, pos);
+                }
+                if let Some(vel) = state.velocity {
                     lazy.insert(entity, vel);
+                }
+                if let Some(ship) = state.ship {
                     lazy.insert(entity, ship);
-                    lazy.insert(
-                        entity,
-                        Replicated {
-                            id: id,
-                            last_update: 0,
-                        },
-                    );
 
                     // Maybe we control this?
-                    if self.controlled_entities.contains(&id) {
-                        warn!("Created locally-controlled ship {}", id);
+                    if local {
+                        info!("Created locally-controlled ship {}", id);
                         lazy.insert(entity, LocalControl);
                     }
-                } else if data.len() == 24 {
-                    let mut data = Cursor::new(data);
-                    let pos = Position {
-                        pos: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let vel = Velocity {
-                        vel: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    assert_eq!(data.position(), 24);
-
-                    let entity = entities.create();
-                    lazy.insert(entity, pos);
-                    lazy.insert(entity, vel);
+                }
+                if let Some((kind, shooter)) = state.projectile {
+                    // The ship that fired it, if we know about it
+                    let shooter = created
+                        .get(&shooter)
+                        .cloned()
+                        .or_else(|| {
+                            (&*entities, &replicated)
+                                .join()
+                                .find(|&(_, r)| r.id == shooter)
+                                .map(|(e, _)| e)
+                        })
+                        .unwrap_or(entity);
+                    lazy.insert(entity, Projectile { kind, shooter });
+                }
+                if kind == EntityKind::Asteroid {
                     lazy.insert(entity, Asteroid);
-                    lazy.insert(
-                        entity,
-                        Replicated {
-                            id: id,
-                            last_update: 0,
-                        },
-                    );
-                } else if data.len() == 25 {
-                    let mut data = Cursor::new(data);
-                    let pos = Position {
-                        pos: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let vel = Velocity {
-                        vel: [read_float(&mut data), read_float(&mut data)],
-                        rot: read_float(&mut data),
-                    };
-                    let kind = match data.read_u8().unwrap() {
-                        1 => ProjectileType::Plasma,
-                        2 => ProjectileType::Rail,
-                        _ => panic!("Got unknown projectile type"),
-                    };
-                    assert_eq!(data.position(), 25);
+                }
+                lazy.insert(
+                    entity,
+                    Replicated {
+                        id,
+                        blocky_revision: None,
+                    },
+                );
 
-                    let entity = entities.create();
-                    lazy.insert(entity, pos);
-                    lazy.insert(entity, vel);
-                    lazy.insert(
-                        entity,
-                        Projectile {
-                            kind,
-                            shooter: entity,
-                        },
-                    );
-                    lazy.insert(
-                        entity,
-                        Replicated {
-                            id: id,
-                            last_update: 0,
-                        },
-                    );
-                } else {
-                    panic!(
-                        "Need to create unknown entity! data {:?} (len {})",
-                        &data[0..50],
-                        data.len(),
-                    );
+                // Attach structure if we got it first
+                if let Some(blk) = self.pending_blocky.remove(&id) {
+                    lazy.insert(entity, blk);
                 }
+                created.insert(id, entity);
             }
         }
 
-        // TODO: Materialize particle effects
-
-        // Go over Dirty, send messages
-        for (ship, repli, _) in (&ship, &replicated, &dirty).join() {
-            let mut flags = 0;
-            if ship.want_fire {
-                flags |= 0x01;
-            }
-            if ship.want_thrust[0] > 0.5 {
-                flags |= 0x02;
-            } else if ship.want_thrust[0] < -0.5 {
-                flags |= 0x04;
+        // Attach structures to entities just created, or keep them for later
+        for &(ref msg, handled) in &messages {
+            if handled {
+                continue;
             }
-            if ship.want_thrust[1] > 0.5 {
-                flags |= 0x08;
+            if let Message::EntityBlocky(id, ref data) = *msg {
+                let blk = match read_blocky(data) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        warn!("Invalid EntityBlocky: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(&entity) = created.get(&id) {
+                    lazy.insert(entity, blk);
+                } else {
+                    self.pending_blocky.insert(id, blk);
+                }
+            } else if let Message::EntityDelete(id) = *msg {
+                self.pending_blocky.remove(&id);
+                self.history.forget(id);
             }
-            if ship.want_thrust_rot > 0.5 {
-                flags |= 0x10;
-            } else if ship.want_thrust_rot < -0.5 {
-                flags |= 0x20;
+        }
+
+        // Show entities as they were a little while ago on the server
+        let delay = self.config.interpolation_delay.as_secs_f32();
+        if let Some(time) = self.clock.advance(dt.0, delay) {
+            let max_extrapolation = self.clock.max_extrapolation();
+            for (pos, vel, buffer) in
+                (&mut position, &mut velocity, &mut snapshots).join()
+            {
+                let sampled = buffer.sample(time, max_extrapolation);
+                if let Some((new_pos, new_vel)) = sampled {
+                    *pos = new_pos;
+                    *vel = new_vel;
+                }
             }
-            let mut data = Vec::with_capacity(9);
-            data.write_u8(flags).unwrap();
-            write_float(&mut data, ship.want_target[0]);
-            write_float(&mut data, ship.want_target[1]);
-            assert_eq!(data.len(), 9);
-            chk(self.send(&Message::EntityUpdate(repli.id, data)))
         }
 
         dirty.clear();
+
+        // Send what our player said, once the server knows us
+        if self.client_id != 0 && !self.reconnecting {
+            for text in chat.outgoing.drain(..) {
+                self.send_reliable(&Message::ChatSend(text));
+            }
+        }
+
+        // Tell the server which states we got, so it can send the next ones
+        // as differences
+        for chunk in acks.chunks(MAX_ACKS_PER_MESSAGE) {
+            self.send(&Message::EntityAck(chunk.to_vec()));
+        }
+
+        self.flush();
+
+        if let Some(s) = self.link.advance(dt.0, STATS_INTERVAL) {
+            *stats = s;
+        }
     }
 }
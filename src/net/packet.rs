@@ -0,0 +1,280 @@
+//! Packets: how messages are put into datagrams.
+//!
+//! Small messages are batched together into datagrams of at most
+//! `MAX_PACKET_SIZE` bytes. Messages too big for one datagram are split into
+//! fragments, which are put back together on the other side.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use log::warn;
+use std::collections::VecDeque;
+use std::io::{Cursor, Read};
+
+use super::codec::DecodeError;
+use super::ORDER;
+
+/// Biggest datagram sent, small enough not to get fragmented by IP on most
+/// links.
+pub const MAX_PACKET_SIZE: usize = 1200;
+
+/// Magic bytes starting every packet.
+const PACKET_MAGIC: &[u8] = b"SPAK";
+
+/// Packet holding whole messages, each preceded by its length.
+const PACKET_BATCH: u8 = 1;
+/// Packet holding a piece of a message: message number, index of the piece
+/// and number of pieces.
+const PACKET_FRAGMENT: u8 = 2;
+
+const BATCH_HEADER: usize = 5;
+const FRAGMENT_HEADER: usize = 11;
+
+/// Most pieces a message can be split into.
+const MAX_FRAGMENTS: u16 = 64;
+
+/// Most fragmented messages being put back together at once. Older ones are
+/// given up on.
+const MAX_PARTIAL_MESSAGES: usize = 8;
+
+/// Packs messages into datagrams, for one peer.
+pub struct Packer {
+    max_size: usize,
+    packets: Vec<Vec<u8>>,
+    /// Whether the last packet is a batch that can take more messages.
+    open: bool,
+    next_message: u16,
+}
+
+impl Packer {
+    /// Creates a packer making datagrams of at most `max_size` bytes.
+    pub fn new(max_size: usize) -> Packer {
+        Packer {
+            max_size,
+            packets: Vec::new(),
+            open: false,
+            next_message: 0,
+        }
+    }
+
+    /// Adds a message.
+    pub fn push(&mut self, msg: &[u8]) {
+        let size = 2 + msg.len();
+        if BATCH_HEADER + size > self.max_size {
+            self.push_fragments(msg);
+            return;
+        }
+
+        match self.packets.last_mut() {
+            Some(packet)
+                if self.open && packet.len() + size <= self.max_size =>
+            {
+                packet.write_u16::<ORDER>(msg.len() as u16).unwrap();
+                packet.extend_from_slice(msg);
+            }
+            _ => {
+                let mut packet = Vec::with_capacity(self.max_size);
+                packet.extend_from_slice(PACKET_MAGIC);
+                packet.write_u8(PACKET_BATCH).unwrap();
+                packet.write_u16::<ORDER>(msg.len() as u16).unwrap();
+                packet.extend_from_slice(msg);
+                self.packets.push(packet);
+                self.open = true;
+            }
+        }
+    }
+
+    fn push_fragments(&mut self, msg: &[u8]) {
+        let chunk_size = self.max_size - FRAGMENT_HEADER;
+        let count = msg.len().div_ceil(chunk_size);
+        if count > MAX_FRAGMENTS as usize {
+            warn!("Not sending message of {} bytes, too big", msg.len());
+            return;
+        }
+        let id = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+        for (index, chunk) in msg.chunks(chunk_size).enumerate() {
+            let mut packet = Vec::with_capacity(FRAGMENT_HEADER + chunk.len());
+            packet.extend_from_slice(PACKET_MAGIC);
+            packet.write_u8(PACKET_FRAGMENT).unwrap();
+            packet.write_u16::<ORDER>(id).unwrap();
+            packet.write_u16::<ORDER>(index as u16).unwrap();
+            packet.write_u16::<ORDER>(count as u16).unwrap();
+            packet.extend_from_slice(chunk);
+            self.packets.push(packet);
+        }
+        self.open = false;
+    }
+
+    /// Takes the datagrams to send.
+    pub fn take(&mut self) -> Vec<Vec<u8>> {
+        self.open = false;
+        std::mem::take(&mut self.packets)
+    }
+}
+
+/// Message being put back together.
+struct Partial {
+    id: u16,
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+/// Gets messages out of datagrams, for one peer.
+pub struct Unpacker {
+    partial: VecDeque<Partial>,
+}
+
+impl Unpacker {
+    pub fn new() -> Unpacker {
+        Unpacker {
+            partial: VecDeque::new(),
+        }
+    }
+
+    /// Reads a datagram, returning the messages that are complete.
+    pub fn unpack(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<Vec<Vec<u8>>, DecodeError> {
+        if packet.len() < PACKET_MAGIC.len()
+            || &packet[..PACKET_MAGIC.len()] != PACKET_MAGIC
+        {
+            return Err(DecodeError::BadMagic);
+        }
+        let mut rdr = Cursor::new(&packet[PACKET_MAGIC.len()..]);
+        match rdr.read_u8()? {
+            PACKET_BATCH => {
+                let mut messages = Vec::new();
+                while (rdr.position() as usize) < rdr.get_ref().len() {
+                    let len = rdr.read_u16::<ORDER>()? as usize;
+                    let mut msg = vec![0; len];
+                    rdr.read_exact(&mut msg)?;
+                    messages.push(msg);
+                }
+                Ok(messages)
+            }
+            PACKET_FRAGMENT => {
+                let id = rdr.read_u16::<ORDER>()?;
+                let index = rdr.read_u16::<ORDER>()? as usize;
+                let count = rdr.read_u16::<ORDER>()?;
+                if count == 0 || count > MAX_FRAGMENTS {
+                    return Err(DecodeError::InvalidValue("fragment count"));
+                }
+                if index >= count as usize {
+                    return Err(DecodeError::InvalidValue("fragment index"));
+                }
+                let mut chunk = Vec::new();
+                rdr.read_to_end(&mut chunk)?;
+                Ok(self.add_fragment(id, index, count as usize, chunk))
+            }
+            _ => Err(DecodeError::InvalidValue("packet type")),
+        }
+    }
+
+    fn add_fragment(
+        &mut self,
+        id: u16,
+        index: usize,
+        count: usize,
+        chunk: Vec<u8>,
+    ) -> Vec<Vec<u8>> {
+        let pos = self.partial.iter().position(|p| p.id == id);
+        let pos = match pos {
+            Some(pos) if self.partial[pos].chunks.len() == count => pos,
+            _ => {
+                // New message, or a reused number
+                if let Some(pos) = pos {
+                    self.partial.remove(pos);
+                }
+                if self.partial.len() >= MAX_PARTIAL_MESSAGES {
+                    self.partial.pop_front();
+                }
+                self.partial.push_back(Partial {
+                    id,
+                    chunks: vec![None; count],
+                    missing: count,
+                });
+                self.partial.len() - 1
+            }
+        };
+
+        let partial = &mut self.partial[pos];
+        if partial.chunks[index].is_none() {
+            partial.chunks[index] = Some(chunk);
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return Vec::new();
+        }
+        let partial = self.partial.remove(pos).unwrap();
+        vec![partial.chunks.into_iter().flatten().flatten().collect()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, Rng};
+
+    use super::{Packer, Unpacker, MAX_PACKET_SIZE};
+
+    #[test]
+    fn test_batching() {
+        let mut packer = Packer::new(MAX_PACKET_SIZE);
+        let messages: Vec<Vec<u8>> =
+            (0..100u8).map(|i| vec![i; 30]).collect();
+        for msg in &messages {
+            packer.push(msg);
+        }
+        let packets = packer.take();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_SIZE));
+        assert!(packer.take().is_empty());
+
+        let mut unpacker = Unpacker::new();
+        let mut received = Vec::new();
+        for packet in &packets {
+            received.extend(unpacker.unpack(packet).unwrap());
+        }
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut rng = rand::thread_rng();
+        let big: Vec<u8> = (0..5000).map(|_| rng.gen()).collect();
+        let mut packer = Packer::new(MAX_PACKET_SIZE);
+        packer.push(b"small");
+        packer.push(&big);
+        packer.push(b"after");
+        let mut packets = packer.take();
+        assert_eq!(packets.len(), 7);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_SIZE));
+
+        // Out of order, with duplicates
+        packets.reverse();
+        packets.push(packets[2].clone());
+        let mut unpacker = Unpacker::new();
+        let mut received = Vec::new();
+        for packet in &packets {
+            received.extend(unpacker.unpack(packet).unwrap());
+        }
+        assert_eq!(received.len(), 3);
+        assert!(received.contains(&big));
+
+        // Too big to send at all
+        packer.push(&vec![0; 100_000]);
+        assert!(packer.take().is_empty());
+    }
+
+    #[test]
+    fn test_random_packets() {
+        let mut rng = rand::thread_rng();
+        let mut unpacker = Unpacker::new();
+        for _ in 0..20000 {
+            let len = rng.gen_range(0, 40);
+            let mut packet = b"SPAK".to_vec();
+            packet.push(rng.gen_range(0, 4));
+            packet.extend((0..len).map(|_| rng.gen::<u8>()));
+            let _ = unpacker.unpack(&packet);
+        }
+    }
+}
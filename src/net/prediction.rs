@@ -0,0 +1,149 @@
+//! Client-side prediction of the ships we control.
+//!
+//! Waiting for the server to apply our controls would make steering feel
+//! sluggish. Instead the client moves its own ships right away, and keeps the
+//! inputs the server hasn't acknowledged yet. When the authoritative state
+//! comes in, it says which input it includes, and the client replays the
+//! later ones on top of it.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::codec::Controls;
+use crate::blocks::Blocky;
+use crate::physics::{Position, Velocity};
+use crate::ship::Ship;
+
+/// Most inputs kept, if the server stops acknowledging them.
+const MAX_PENDING_INPUTS: usize = 256;
+
+/// Whether sequence number `a` comes after `b`, accounting for wrapping.
+pub fn seq_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// An input sent to the server, applied for a frame.
+struct PendingInput {
+    seq: u32,
+    entity: u64,
+    dt: f32,
+    controls: Controls,
+}
+
+/// Inputs sent to the server, and the last one it acknowledged.
+pub struct Prediction {
+    last_seq: u32,
+    inputs: VecDeque<PendingInput>,
+    acked: HashMap<u64, u32>,
+}
+
+impl Prediction {
+    pub fn new() -> Prediction {
+        Prediction {
+            last_seq: 0,
+            inputs: VecDeque::new(),
+            acked: HashMap::new(),
+        }
+    }
+
+    /// Gets the sequence number for this frame's inputs.
+    pub fn next_seq(&mut self) -> u32 {
+        self.last_seq = self.last_seq.wrapping_add(1);
+        self.last_seq
+    }
+
+    /// Records an input that was applied locally and sent.
+    pub fn record(
+        &mut self,
+        seq: u32,
+        entity: u64,
+        dt: f32,
+        controls: Controls,
+    ) {
+        self.inputs.push_back(PendingInput {
+            seq,
+            entity,
+            dt,
+            controls,
+        });
+        if self.inputs.len() > MAX_PENDING_INPUTS {
+            self.inputs.pop_front();
+        }
+    }
+
+    /// Records that the server applied inputs for an entity up to `seq`.
+    ///
+    /// Returns false if a more recent state was already received, in which
+    /// case this one should be ignored.
+    pub fn acknowledge(&mut self, entity: u64, seq: u32) -> bool {
+        if let Some(&acked) = self.acked.get(&entity) {
+            if seq_newer(acked, seq) {
+                return false;
+            }
+        }
+        self.acked.insert(entity, seq);
+        self.inputs
+            .retain(|i| i.entity != entity || seq_newer(i.seq, seq));
+        true
+    }
+
+    /// Forgets about an entity that is gone.
+    pub fn forget(&mut self, entity: u64) {
+        self.acked.remove(&entity);
+        self.inputs.retain(|i| i.entity != entity);
+    }
+
+    /// Re-applies the inputs the server hasn't seen yet to a ship, the same
+    /// way `SysSimu` and `SysShip` did when they were first applied.
+    pub fn replay(
+        &self,
+        entity: u64,
+        pos: &mut Position,
+        vel: &mut Velocity,
+        ship: &mut Ship,
+        blocky: &Blocky,
+    ) {
+        for input in self.inputs.iter().filter(|i| i.entity == entity) {
+            pos.advance(vel, input.dt);
+            input.controls.apply(ship);
+            ship.update_thrust(blocky);
+            ship.accelerate(pos, vel, blocky, input.dt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{seq_newer, Prediction};
+    use crate::net::codec::Controls;
+
+    fn controls() -> Controls {
+        Controls {
+            fire: false,
+            thrust: [1.0, 0.0],
+            thrust_rot: 0.0,
+            target: [0.0, 0.0],
+            view_tick: 0,
+        }
+    }
+
+    #[test]
+    fn test_acknowledge() {
+        assert!(seq_newer(1, u32::MAX));
+        assert!(!seq_newer(u32::MAX, 1));
+
+        let mut prediction = Prediction::new();
+        for _ in 0..3 {
+            let seq = prediction.next_seq();
+            prediction.record(seq, 1, 0.02, controls());
+            prediction.record(seq, 2, 0.02, controls());
+        }
+        assert!(prediction.acknowledge(1, 2));
+        assert_eq!(prediction.inputs.len(), 4);
+        // Older state, out of order
+        assert!(!prediction.acknowledge(1, 1));
+        assert_eq!(prediction.inputs.len(), 4);
+        prediction.forget(2);
+        assert_eq!(prediction.inputs.len(), 1);
+        assert_eq!(prediction.inputs[0].seq, 3);
+    }
+}
@@ -0,0 +1,143 @@
+//! Picking which entity updates to send, within a bandwidth budget.
+//!
+//! For each client, every entity it knows has a priority that grows each
+//! tick by how much the entity matters to that client: its kind, how close
+//! and how fast it is, and whether something unexpected happened to it.
+//! Entities are sent highest priority first until the tick's budget is used
+//! up, and their priority goes back to zero. The ones left out keep
+//! accumulating, so even distant asteroids get their turn.
+
+use std::collections::HashMap;
+
+use super::codec::{EntityKind, QuantizedState};
+
+/// Priority an entity needs to reach before it is worth sending.
+const SEND_THRESHOLD: f32 = 10.0;
+
+/// Distance at which an entity's priority is halved.
+const DISTANCE_SCALE: f32 = 20.0;
+
+/// Speed at which an entity's priority is doubled.
+const SPEED_SCALE: f32 = 10.0;
+
+/// Priority added when an entity changed in a way clients can't predict.
+const DIRTY_PRIORITY: f32 = SEND_THRESHOLD;
+
+/// Factor applied to the ships the client controls, so it gets its inputs
+/// acknowledged quickly.
+const OWNED_FACTOR: f32 = 4.0;
+
+fn kind_weight(kind: EntityKind) -> f32 {
+    match kind {
+        EntityKind::Ship => 4.0,
+        EntityKind::Projectile => 2.0,
+        EntityKind::Debris => 1.0,
+        EntityKind::Asteroid => 1.0,
+    }
+}
+
+/// How much the priority of an entity grows in a tick.
+pub fn priority(
+    kind: EntityKind,
+    distance: f32,
+    speed: f32,
+    dirty: bool,
+    owned: bool,
+) -> f32 {
+    let mut priority = kind_weight(kind)
+        * (1.0 + speed / SPEED_SCALE)
+        / (1.0 + distance / DISTANCE_SCALE);
+    if owned {
+        priority *= OWNED_FACTOR;
+    }
+    if dirty {
+        priority += DIRTY_PRIORITY;
+    }
+    priority
+}
+
+/// State of an entity that might get sent this tick.
+pub struct Update {
+    pub id: u64,
+    pub kind: EntityKind,
+    pub state: QuantizedState,
+}
+
+/// Accumulated priorities of the entities a client knows.
+pub struct Priorities {
+    accumulated: HashMap<u64, f32>,
+    queued: Vec<(f32, Update)>,
+}
+
+impl Priorities {
+    pub fn new() -> Priorities {
+        Priorities {
+            accumulated: HashMap::new(),
+            queued: Vec::new(),
+        }
+    }
+
+    /// Adds to the priority of an entity, and queues its current state.
+    pub fn push(&mut self, update: Update, priority: f32) {
+        let accumulated = self.accumulated.entry(update.id).or_insert(0.0);
+        *accumulated += priority;
+        if *accumulated >= SEND_THRESHOLD {
+            self.queued.push((*accumulated, update));
+        }
+    }
+
+    /// Takes the updates worth sending this tick, highest priority first.
+    ///
+    /// Call `sent()` for each one that makes it into the budget.
+    pub fn take(&mut self) -> Vec<Update> {
+        let mut queued = std::mem::take(&mut self.queued);
+        queued.sort_by(|a, b| b.0.total_cmp(&a.0));
+        queued.into_iter().map(|(_, update)| update).collect()
+    }
+
+    /// Records that an entity was sent, resetting its priority.
+    pub fn sent(&mut self, id: u64) {
+        self.accumulated.insert(id, 0.0);
+    }
+
+    /// Forgets about an entity the client no longer knows.
+    pub fn forget(&mut self, id: u64) {
+        self.accumulated.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{priority, Priorities, Update};
+    use crate::net::codec::{EntityKind, EntityState};
+
+    fn update(id: u64) -> Update {
+        Update {
+            id,
+            kind: EntityKind::Asteroid,
+            state: EntityState::default().quantize(),
+        }
+    }
+
+    #[test]
+    fn test_priorities() {
+        let near = priority(EntityKind::Ship, 5.0, 20.0, false, false);
+        let far = priority(EntityKind::Asteroid, 80.0, 5.0, false, false);
+        assert!(near > 4.0 * far);
+        assert!(priority(EntityKind::Asteroid, 80.0, 5.0, true, false) > 10.0);
+
+        let mut priorities = Priorities::new();
+        let mut sent = [0; 2];
+        for _ in 0..100 {
+            priorities.push(update(0), near);
+            priorities.push(update(1), far);
+            // Room for one update per tick
+            if let Some(update) = priorities.take().into_iter().next() {
+                sent[update.id as usize] += 1;
+                priorities.sent(update.id);
+            }
+        }
+        assert!(sent[0] > 4 * sent[1]);
+        assert!(sent[1] > 0);
+    }
+}
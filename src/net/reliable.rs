@@ -0,0 +1,212 @@
+//! Reliable, ordered delivery of messages that can't get lost.
+//!
+//! Most of what the server sends is state that is sent again anyway, so it
+//! goes as is. Messages that are only sent once, like the client's ID or
+//! entities being deleted, are numbered and wrapped in `Message::Reliable`
+//! instead. The receiving side hands them over in order and acknowledges
+//! them with `Message::ReliableAck`, and the sending side resends them until
+//! they are. Clients use it the other way for the few messages they send
+//! once, like chat.
+//!
+//! Times are given by the caller, from whatever starting point it likes,
+//! since there is no clock in the browser.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use super::codec::Message;
+
+/// How long to wait for an acknowledgement before sending again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Most messages kept by the receiving side while waiting for an earlier
+/// one.
+const MAX_BUFFERED: u16 = 256;
+
+/// Most messages kept by the sending side waiting for an acknowledgement.
+/// Past that, the other side is not keeping up.
+const MAX_PENDING: usize = 1024;
+
+/// Whether sequence number `a` comes after `b`, accounting for wrapping.
+fn seq_newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+struct Pending {
+    seq: u16,
+    msg: Vec<u8>,
+    /// Unknown until the first call to `resend()` after it was pushed,
+    /// which is about when it went out.
+    last_sent: Option<Duration>,
+}
+
+/// Sending side of a reliable channel.
+pub struct ReliableSender {
+    next_seq: u16,
+    pending: VecDeque<Pending>,
+}
+
+impl ReliableSender {
+    pub fn new() -> ReliableSender {
+        ReliableSender {
+            next_seq: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Adds a message, returning what to send right away.
+    pub fn push(&mut self, msg: &Message) -> Message {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let msg = msg.bytes();
+        self.pending.push_back(Pending {
+            seq,
+            msg: msg.clone(),
+            last_sent: None,
+        });
+        Message::Reliable(seq, msg)
+    }
+
+    /// Sequence number the next message pushed will get.
+    pub fn next_seq(&self) -> u16 {
+        self.next_seq
+    }
+
+    /// Whether the other side acknowledged a message.
+    pub fn delivered(&self, seq: u16) -> bool {
+        !self.pending.iter().any(|p| p.seq == seq)
+    }
+
+    /// Records that the other side got every message before `next`.
+    pub fn acknowledge(&mut self, next: u16) {
+        // Ignore acknowledgements for messages we didn't send
+        if seq_newer(next, self.next_seq) {
+            return;
+        }
+        self.pending.retain(|p| !seq_newer(next, p.seq));
+    }
+
+    /// Whether too many messages are waiting for an acknowledgement.
+    pub fn overflowed(&self) -> bool {
+        self.pending.len() > MAX_PENDING
+    }
+
+    /// Gets the messages that haven't been acknowledged in a while, to send
+    /// them again.
+    pub fn resend(&mut self, now: Duration) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for pending in &mut self.pending {
+            let last_sent = *pending.last_sent.get_or_insert(now);
+            if now.saturating_sub(last_sent) >= RESEND_INTERVAL {
+                pending.last_sent = Some(now);
+                let msg = pending.msg.clone();
+                messages.push(Message::Reliable(pending.seq, msg));
+            }
+        }
+        messages
+    }
+}
+
+/// Receiving side of a reliable channel.
+pub struct ReliableReceiver {
+    next_seq: u16,
+    buffered: HashMap<u16, Vec<u8>>,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> ReliableReceiver {
+        ReliableReceiver {
+            next_seq: 0,
+            buffered: HashMap::new(),
+        }
+    }
+
+    /// Adds a message, returning the ones that can now be delivered, in
+    /// order.
+    pub fn receive(&mut self, seq: u16, msg: Vec<u8>) -> Vec<Vec<u8>> {
+        // Already delivered, or too far ahead
+        if seq_newer(self.next_seq, seq)
+            || seq.wrapping_sub(self.next_seq) >= MAX_BUFFERED
+        {
+            return Vec::new();
+        }
+        self.buffered.insert(seq, msg);
+
+        let mut messages = Vec::new();
+        while let Some(msg) = self.buffered.remove(&self.next_seq) {
+            messages.push(msg);
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        messages
+    }
+
+    /// Sequence number of the next message expected, acknowledging all the
+    /// ones before.
+    pub fn ack(&self) -> u16 {
+        self.next_seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+    use rand::{self, Rng};
+    use std::time::Duration;
+
+    use super::{ReliableReceiver, ReliableSender, MAX_PENDING};
+    use crate::net::codec::Message;
+
+    #[test]
+    fn test_lossy_link() {
+        let mut rng = rand::thread_rng();
+        let mut sender = ReliableSender::new();
+        let mut receiver = ReliableReceiver::new();
+        let mut in_flight = Vec::new();
+        for i in 0..50 {
+            in_flight.push(sender.push(&Message::EntityDelete(i)));
+        }
+
+        let mut delivered = Vec::new();
+        let mut now = Duration::from_secs(0);
+        for _ in 0..100 {
+            // Lose and reorder
+            in_flight.retain(|_| rng.gen::<f32>() < 0.6);
+            in_flight.shuffle(&mut rng);
+            for msg in in_flight.drain(..) {
+                if let Message::Reliable(seq, data) = msg {
+                    for m in receiver.receive(seq, data) {
+                        delivered.push(Message::parse(&m).unwrap());
+                    }
+                }
+            }
+            if rng.gen::<f32>() < 0.6 {
+                sender.acknowledge(receiver.ack());
+            }
+            now += Duration::from_secs(1);
+            in_flight = sender.resend(now);
+        }
+
+        let expected: Vec<_> = (0..50).map(Message::EntityDelete).collect();
+        assert_eq!(delivered, expected);
+        sender.acknowledge(receiver.ack());
+        assert!(sender.resend(now + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut sender = ReliableSender::new();
+        for i in 0..MAX_PENDING as u64 {
+            sender.push(&Message::EntityDelete(i));
+        }
+        assert!(!sender.overflowed());
+        let seq = sender.next_seq();
+        sender.push(&Message::EntityDelete(0));
+        assert!(sender.overflowed());
+        assert!(!sender.delivered(seq));
+
+        // Catching up clears it
+        sender.acknowledge(sender.next_seq());
+        assert!(!sender.overflowed());
+        assert!(sender.delivered(seq));
+    }
+}
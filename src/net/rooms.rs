@@ -0,0 +1,430 @@
+//! Several independent games behind one socket.
+//!
+//! Each room is a `Game` with its own `SysNetServer`, talking through a
+//! `RoomServer` that gets the packets meant for it from `Rooms`. Clients
+//! name the room they want in their `ClientHello`, which creates it if
+//! needed, and get IDs starting with the room's, so their later packets are
+//! routed without looking inside them. Rooms are torn down once nobody is
+//! left in them.
+//!
+//! Packets from clients that aren't in a room, asking to join one or about
+//! the server, are answered here, within a rate limit per address.
+
+use byteorder::ReadBytesExt;
+use log::{info, warn};
+use specs::WorldExt;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::codec::{DisconnectReason, Message, ServerInfo};
+use super::limits::RateLimiter;
+use super::packet::{Packer, Unpacker, MAX_PACKET_SIZE};
+use super::{chk, Admin, Server, ServerConfig, MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION, ORDER};
+use crate::{Game, WorldConfig};
+
+type Queue<A> = Arc<Mutex<VecDeque<(Vec<u8>, A)>>>;
+
+/// Sends a message right away.
+fn send<S: Server>(
+    server: &S,
+    msg: &Message,
+    addr: &S::Address,
+) -> io::Result<usize> {
+    let mut packer = Packer::new(MAX_PACKET_SIZE);
+    packer.push(&msg.bytes());
+    let mut sent = 0;
+    for packet in packer.take() {
+        sent += server.send(&packet, addr)?;
+    }
+    Ok(sent)
+}
+
+/// The socket as seen from one room: packets come from the room's queue,
+/// and go out directly.
+pub struct RoomServer<S: Server> {
+    server: Arc<Mutex<S>>,
+    queue: Queue<S::Address>,
+}
+
+impl<S: Server> Server for RoomServer<S> {
+    type Address = S::Address;
+
+    fn send(&self, msg: &[u8], addr: &S::Address) -> io::Result<usize> {
+        self.server.lock().unwrap().send(msg, addr)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, S::Address)> {
+        match self.queue.lock().unwrap().pop_front() {
+            Some((data, addr)) => {
+                buffer[..data.len()].copy_from_slice(&data);
+                Ok((data.len(), addr))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// Settings for the rooms.
+#[derive(Clone)]
+pub struct RoomsConfig {
+    /// Settings of the server in each room. `max_clients` is per room.
+    pub server: ServerConfig,
+    /// Settings of the world in each room.
+    pub world: WorldConfig,
+    /// Most rooms open at once.
+    pub max_rooms: usize,
+    /// Packets per second accepted from each address that isn't in a room,
+    /// asking to join one or about the server.
+    pub max_request_rate: f32,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> RoomsConfig {
+        RoomsConfig {
+            server: ServerConfig::default(),
+            world: WorldConfig::default(),
+            max_rooms: 8,
+            max_request_rate: 4.0,
+        }
+    }
+}
+
+struct Room<A> {
+    game: Game,
+    queue: Queue<A>,
+}
+
+/// What a packet from a client that isn't connected is about.
+enum Request {
+    Join(u32),
+    Discovery,
+}
+
+/// Hosts games, routing the packets from one server to them.
+pub struct Rooms<S: Server> {
+    server: Arc<Mutex<S>>,
+    config: RoomsConfig,
+    rooms: BTreeMap<u32, Room<S::Address>>,
+    limiter: RateLimiter<S::Address>,
+}
+
+impl<S: Server> Rooms<S> {
+    pub fn new(server: S, config: RoomsConfig) -> Rooms<S> {
+        Rooms {
+            server: Arc::new(Mutex::new(server)),
+            limiter: RateLimiter::new(config.max_request_rate),
+            config,
+            rooms: BTreeMap::new(),
+        }
+    }
+
+    /// The open rooms, by ID.
+    pub fn rooms(&self) -> impl Iterator<Item = (u32, &Game)> {
+        self.rooms.iter().map(|(&id, room)| (id, &room.game))
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Game> {
+        self.rooms.get(&id).map(|room| &room.game)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Game> {
+        self.rooms.get_mut(&id).map(|room| &mut room.game)
+    }
+
+    /// Routes the packets that came in, and updates all the rooms.
+    pub fn update(&mut self, dt: f32) {
+        self.route();
+        for room in self.rooms.values_mut() {
+            room.game.update(dt);
+        }
+
+        // Close the rooms nobody is in, or coming back to
+        self.rooms.retain(|&id, room| {
+            let admin = room.game.world.read_resource::<Admin>();
+            let empty = admin.clients.is_empty() && admin.reconnecting == 0;
+            if empty {
+                info!("Closing empty room {}", id);
+            }
+            !empty
+        });
+    }
+
+    /// Disconnects the clients of all the rooms, and closes them.
+    pub fn shutdown(&mut self, dt: f32) {
+        for room in self.rooms.values_mut() {
+            room.game.world.write_resource::<Admin>().shutdown();
+            room.game.update(dt);
+        }
+        self.rooms.clear();
+    }
+
+    /// Describes the server to clients looking for one.
+    fn info(&self) -> ServerInfo {
+        let players: usize = self
+            .rooms
+            .values()
+            .map(|room| {
+                room.game.world.read_resource::<Admin>().clients.len()
+            })
+            .sum();
+        let max_players =
+            self.config.server.max_clients * self.config.max_rooms;
+        ServerInfo {
+            name: self.config.server.name.clone(),
+            players: players.min(u16::MAX as usize) as u16,
+            max_players: max_players.min(u16::MAX as usize) as u16,
+            map: self.config.server.map.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Creates a room, unless there are too many already.
+    fn open(&mut self, id: u32) -> bool {
+        if self.rooms.len() >= self.config.max_rooms {
+            return false;
+        }
+        info!("Opening room {}", id);
+        let queue = Queue::default();
+        let server = RoomServer {
+            server: self.server.clone(),
+            queue: queue.clone(),
+        };
+        let config = ServerConfig {
+            room: id,
+            ..self.config.server.clone()
+        };
+        let mut game = Game::new_server(server, config);
+        game.world.insert(self.config.world.clone());
+        self.rooms.insert(id, Room { game, queue });
+        true
+    }
+
+    /// Sends a message right away, to a client that isn't in a room.
+    fn reply(&self, msg: &Message, addr: &S::Address) {
+        chk(send(&*self.server.lock().unwrap(), msg, addr));
+    }
+
+    /// Hands the packets that came in to the rooms they are for.
+    fn route(&mut self) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let instant = Instant::now();
+        self.limiter.cleanup(instant);
+        loop {
+            let recv = self.server.lock().unwrap().recv(&mut buffer);
+            let (len, src) = match recv {
+                Ok(r) => r,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        warn!("Error reading from socket: {}", e);
+                    }
+                    break;
+                }
+            };
+            if len < 16 {
+                info!("Invalid message from {}: no client ID", src);
+                continue;
+            }
+            let packet = &buffer[..len];
+
+            // Connected clients have the room in their ID
+            let client_id = (&packet[0..]).read_u64::<ORDER>().unwrap();
+            let id = if client_id != 0 {
+                (client_id >> 32) as u32
+            } else {
+                if !self.limiter.allow(src.clone(), instant) {
+                    info!("Too many requests from {}", src);
+                    continue;
+                }
+                match read_request(&packet[16..]) {
+                    Some(Request::Join(id)) => {
+                        if !self.rooms.contains_key(&id) && !self.open(id) {
+                            warn!("Too many rooms, turning away {}", src);
+                            let reason = DisconnectReason::ServerFull;
+                            self.reply(&Message::Disconnect(reason), &src);
+                            continue;
+                        }
+                        id
+                    }
+                    Some(Request::Discovery) => {
+                        let info = self.info();
+                        self.reply(&Message::DiscoveryResponse(info), &src);
+                        continue;
+                    }
+                    // Leftovers from clients that got disconnected
+                    None => continue,
+                }
+            };
+            let room = match self.rooms.get(&id) {
+                Some(room) => room,
+                None => {
+                    info!("Packet from {} for closed room {}", src, id);
+                    let reason = DisconnectReason::UnknownSession;
+                    self.reply(&Message::Disconnect(reason), &src);
+                    continue;
+                }
+            };
+            room.queue.lock().unwrap().push_back((packet.to_vec(), src));
+        }
+    }
+}
+
+/// Finds what a client that isn't connected wants.
+fn read_request(packet: &[u8]) -> Option<Request> {
+    // Not connected yet, nothing is big enough to be fragmented
+    let messages = Unpacker::new().unpack(packet).ok()?;
+    messages.iter().find_map(|msg| match Message::parse(msg) {
+        Ok(Message::ClientHello(_, room, _, _)) => Some(Request::Join(room)),
+        Ok(Message::DiscoveryRequest) => Some(Request::Discovery),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Join, WorldExt};
+
+    use super::{Rooms, RoomsConfig};
+    use crate::Game;
+    use crate::net::codec::Message;
+    use crate::net::packet::{Packer, MAX_PACKET_SIZE};
+    use crate::net::discovery::{read_response, request_packet};
+    use crate::net::loopback::{Connector, LoopbackServer};
+    use crate::net::{Client, ClientConfig, ServerConfig, PROTOCOL_VERSION};
+    use crate::physics::LocalControl;
+    use crate::ship::Ship;
+
+    fn client(connector: &Connector, room: u32) -> Game {
+        let config = ClientConfig {
+            room,
+            ..Default::default()
+        };
+        Game::new_client(connector.connect(Default::default()), config)
+    }
+
+    fn run(rooms: &mut Rooms<LoopbackServer>, clients: &mut [Game]) {
+        for _ in 0..5 {
+            rooms.update(0.080);
+            for client in clients.iter_mut() {
+                client.update(0.080);
+            }
+        }
+    }
+
+    fn count_ships(game: &Game) -> (usize, usize) {
+        let ships = game.world.read_component::<Ship>();
+        let local = game.world.read_component::<LocalControl>();
+        ((&ships).join().count(), (&ships, &local).join().count())
+    }
+
+    #[test]
+    fn test_rooms() {
+        let server = LoopbackServer::new();
+        let connector = server.connector();
+        let config = RoomsConfig {
+            max_rooms: 2,
+            ..Default::default()
+        };
+        let mut rooms = Rooms::new(server, config);
+        let mut clients = vec![
+            client(&connector, 0),
+            client(&connector, 7),
+            client(&connector, 7),
+        ];
+        run(&mut rooms, &mut clients);
+
+        // Rooms got created, and only see their own clients
+        let ids: Vec<u32> = rooms.rooms().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0, 7]);
+        assert_eq!(count_ships(rooms.get(0).unwrap()).0, 1);
+        assert_eq!(count_ships(rooms.get(7).unwrap()).0, 2);
+        assert_eq!(count_ships(&clients[0]), (1, 1));
+        assert_eq!(count_ships(&clients[1]), (2, 1));
+
+        // No room for a third one
+        clients.push(client(&connector, 3));
+        run(&mut rooms, &mut clients);
+        assert!(rooms.get(3).is_none());
+        assert_eq!(count_ships(&clients[3]), (0, 0));
+
+        // Discovery counts the players of all rooms
+        let discovery = connector.connect(Default::default());
+        discovery.send(&request_packet()).unwrap();
+        rooms.update(0.080);
+        let mut buffer = [0; 1500];
+        let len = discovery.recv(&mut buffer).unwrap();
+        let info = read_response(&buffer[..len]).unwrap();
+        assert_eq!(info.players, 3);
+
+        // Room goes away once empty
+        clients.truncate(1);
+        run(&mut rooms, &mut clients);
+        let ids: Vec<u32> = rooms.rooms().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0]);
+    }
+
+    #[test]
+    fn test_discovery() {
+        let server = LoopbackServer::new();
+        let connector = server.connector();
+        let config = RoomsConfig {
+            server: ServerConfig {
+                name: "Test server".to_owned(),
+                max_clients: 4,
+                ..Default::default()
+            },
+            max_rooms: 8,
+            max_request_rate: 3.0,
+            ..Default::default()
+        };
+        let mut rooms = Rooms::new(server, config);
+        let mut clients = vec![client(&connector, 0)];
+        run(&mut rooms, &mut clients);
+
+        let looking = connector.connect(Default::default());
+        looking.send(&request_packet()).unwrap();
+        rooms.update(0.080);
+        let mut buffer = [0; 1500];
+        let len = looking.recv(&mut buffer).unwrap();
+        let info = read_response(&buffer[..len]).unwrap();
+        assert_eq!(info.name, "Test server");
+        assert_eq!((info.players, info.max_players), (1, 32));
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert!(info.compatible());
+
+        // Looking doesn't join
+        assert_eq!(count_ships(rooms.get(0).unwrap()).0, 1);
+
+        // Asking too often gets ignored
+        for _ in 0..10 {
+            looking.send(&request_packet()).unwrap();
+        }
+        rooms.update(0.080);
+        let mut answers = 0;
+        while looking.recv(&mut buffer).is_ok() {
+            answers += 1;
+        }
+        assert_eq!(answers, 2);
+
+        // And so does trying to open rooms
+        let flooding = connector.connect(Default::default());
+        for room in 1..10 {
+            let hello = Message::ClientHello(
+                PROTOCOL_VERSION,
+                room,
+                false,
+                String::new(),
+            );
+            let mut packer = Packer::new(MAX_PACKET_SIZE - 16);
+            packer.push(&hello.bytes());
+            let mut packet = vec![0; 16];
+            packet.extend_from_slice(&packer.take()[0]);
+            flooding.send(&packet).unwrap();
+        }
+        rooms.update(0.080);
+        assert_eq!(rooms.rooms().count(), 4);
+    }
+}
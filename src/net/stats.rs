@@ -0,0 +1,231 @@
+//! Network statistics: round-trip time, packet loss and bandwidth.
+//!
+//! Each peer's link is measured by a `LinkStats`, and the figures are
+//! published regularly as the `NetStats` resource. On the server, that is
+//! the combination of all the clients' links.
+
+use std::collections::HashMap;
+
+use super::codec::message_type;
+
+/// How much a new round-trip time moves the average.
+const RTT_WEIGHT: f32 = 0.125;
+
+/// How much a new round-trip time moves the jitter, as in RFC 3550.
+const JITTER_WEIGHT: f32 = 0.0625;
+
+/// How much each ping moves the packet loss estimate.
+const LOSS_WEIGHT: f32 = 0.1;
+
+/// Network statistics, available as a resource.
+///
+/// Rates are over the last interval, see `STATS_INTERVAL`.
+#[derive(Debug, Default, Clone)]
+pub struct NetStats {
+    /// Round-trip time, in seconds. Averaged over clients on the server.
+    pub rtt: f32,
+    /// Variation of the round-trip time, in seconds.
+    pub jitter: f32,
+    /// Fraction of pings that got no answer, from 0 to 1.
+    pub packet_loss: f32,
+    /// Bytes received per second.
+    pub bytes_in: f32,
+    /// Bytes sent per second.
+    pub bytes_out: f32,
+    /// Messages received per second, by type.
+    pub messages_in: HashMap<&'static str, f32>,
+    /// Messages sent per second, by type.
+    pub messages_out: HashMap<&'static str, f32>,
+}
+
+impl NetStats {
+    /// Combines the statistics of several links, for the server.
+    pub fn combine<'a, I: IntoIterator<Item = &'a NetStats>>(
+        links: I,
+    ) -> NetStats {
+        let mut total = NetStats::default();
+        let mut count = 0;
+        for link in links {
+            count += 1;
+            total.rtt += link.rtt;
+            total.jitter += link.jitter;
+            total.packet_loss += link.packet_loss;
+            total.bytes_in += link.bytes_in;
+            total.bytes_out += link.bytes_out;
+            for (&t, &n) in &link.messages_in {
+                *total.messages_in.entry(t).or_insert(0.0) += n;
+            }
+            for (&t, &n) in &link.messages_out {
+                *total.messages_out.entry(t).or_insert(0.0) += n;
+            }
+        }
+        if count > 0 {
+            total.rtt /= count as f32;
+            total.jitter /= count as f32;
+            total.packet_loss /= count as f32;
+        }
+        total
+    }
+
+    /// Formats the message rates, most frequent first.
+    pub fn format_messages(messages: &HashMap<&'static str, f32>) -> String {
+        let mut messages: Vec<_> = messages.iter().collect();
+        messages.sort_by(|a, b| b.1.total_cmp(a.1).then(a.0.cmp(b.0)));
+        let messages: Vec<_> = messages
+            .into_iter()
+            .map(|(t, n)| format!("{} {:.1}", t, n))
+            .collect();
+        messages.join(", ")
+    }
+}
+
+/// Measures the link with one peer.
+pub struct LinkStats {
+    rtt: Option<f32>,
+    jitter: f32,
+    loss: f32,
+    awaiting_pong: bool,
+    bytes_in: u64,
+    bytes_out: u64,
+    messages_in: HashMap<&'static str, u32>,
+    messages_out: HashMap<&'static str, u32>,
+    /// Seconds the counters have been running for.
+    elapsed: f32,
+}
+
+impl LinkStats {
+    pub fn new() -> LinkStats {
+        LinkStats {
+            rtt: None,
+            jitter: 0.0,
+            loss: 0.0,
+            awaiting_pong: false,
+            bytes_in: 0,
+            bytes_out: 0,
+            messages_in: HashMap::new(),
+            messages_out: HashMap::new(),
+            elapsed: 0.0,
+        }
+    }
+
+    /// Smoothed round-trip time, in seconds, 0 until measured.
+    pub fn rtt(&self) -> f32 {
+        self.rtt.unwrap_or(0.0)
+    }
+
+    /// Records a ping going out. If the previous one didn't get an answer,
+    /// it is counted as lost.
+    pub fn ping_sent(&mut self) {
+        if self.awaiting_pong {
+            self.loss += (1.0 - self.loss) * LOSS_WEIGHT;
+        }
+        self.awaiting_pong = true;
+    }
+
+    /// Records the answer to a ping, that took `rtt` seconds.
+    pub fn pong_received(&mut self, rtt: f32) {
+        if self.awaiting_pong {
+            self.awaiting_pong = false;
+            self.loss -= self.loss * LOSS_WEIGHT;
+        }
+        match self.rtt {
+            Some(ref mut avg) => {
+                self.jitter += ((rtt - *avg).abs() - self.jitter)
+                    * JITTER_WEIGHT;
+                *avg += (rtt - *avg) * RTT_WEIGHT;
+            }
+            None => self.rtt = Some(rtt),
+        }
+    }
+
+    pub fn packet_sent(&mut self, len: usize) {
+        self.bytes_out += len as u64;
+    }
+
+    pub fn packet_received(&mut self, len: usize) {
+        self.bytes_in += len as u64;
+    }
+
+    /// Counts an encoded message going out.
+    pub fn message_sent(&mut self, msg: &[u8]) {
+        *self.messages_out.entry(message_type(msg)).or_insert(0) += 1;
+    }
+
+    /// Counts an encoded message coming in.
+    pub fn message_received(&mut self, msg: &[u8]) {
+        *self.messages_in.entry(message_type(msg)).or_insert(0) += 1;
+    }
+
+    /// Moves time forward. Returns the statistics once `interval` seconds
+    /// have passed, starting the counters over.
+    pub fn advance(&mut self, dt: f32, interval: f32) -> Option<NetStats> {
+        self.elapsed += dt;
+        if self.elapsed < interval {
+            return None;
+        }
+        let secs = self.elapsed;
+        let rate = |counts: &mut HashMap<&'static str, u32>| {
+            counts.drain().map(|(t, n)| (t, n as f32 / secs)).collect()
+        };
+        let stats = NetStats {
+            rtt: self.rtt(),
+            jitter: self.jitter,
+            packet_loss: self.loss,
+            bytes_in: self.bytes_in as f32 / secs,
+            bytes_out: self.bytes_out as f32 / secs,
+            messages_in: rate(&mut self.messages_in),
+            messages_out: rate(&mut self.messages_out),
+        };
+        self.bytes_in = 0;
+        self.bytes_out = 0;
+        self.elapsed = 0.0;
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkStats, NetStats};
+    use crate::net::codec::Message;
+
+    #[test]
+    fn test_link_stats() {
+        let mut link = LinkStats::new();
+
+        // Round-trip time settles, with some jitter
+        for i in 0..50 {
+            link.ping_sent();
+            link.pong_received(if i % 2 == 0 { 0.09 } else { 0.11 });
+        }
+        assert!((link.rtt() - 0.1).abs() < 0.005);
+        assert!(link.jitter > 0.005 && link.jitter < 0.03);
+        assert_eq!(link.loss, 0.0);
+
+        // Unanswered pings count as lost
+        for _ in 0..5 {
+            link.ping_sent();
+        }
+        assert!(link.loss > 0.3 && link.loss < 0.4);
+
+        // Rates
+        link.packet_sent(300);
+        link.packet_received(100);
+        link.message_sent(&Message::Ping(1).bytes());
+        link.message_sent(&Message::Ping(2).bytes());
+        link.message_received(&Message::ClientBye.bytes());
+        assert!(link.advance(1.0, 2.0).is_none());
+        let stats = link.advance(1.0, 2.0).unwrap();
+        assert_eq!(stats.bytes_out, 150.0);
+        assert_eq!(stats.bytes_in, 50.0);
+        assert_eq!(stats.messages_out["Ping"], 1.0);
+        assert_eq!(stats.messages_in["ClientBye"], 0.5);
+
+        // Counters start over
+        let stats = link.advance(1.0, 1.0).unwrap();
+        assert_eq!(stats.bytes_out, 0.0);
+        assert!(stats.messages_out.is_empty());
+
+        let total = NetStats::combine(&[stats.clone(), stats]);
+        assert!((total.rtt - link.rtt()).abs() < 0.001);
+    }
+}
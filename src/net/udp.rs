@@ -11,14 +11,17 @@ pub struct UdpServer {
 impl UdpServer {
     pub fn new(port: u16) -> UdpServer {
         let unspec = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-        let socket = match UdpSocket::bind(SocketAddr::new(unspec, port)) {
+        match UdpServer::bind(SocketAddr::new(unspec, port)) {
             Ok(s) => s,
             Err(e) => panic!("Couldn't listen on port {}: {}", port, e),
-        };
-        socket
-            .set_nonblocking(true)
-            .expect("Couldn't set socket nonblocking");
-        UdpServer { socket }
+        }
+    }
+
+    /// Listens on the given address.
+    pub fn bind(address: SocketAddr) -> io::Result<UdpServer> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpServer { socket })
     }
 }
 
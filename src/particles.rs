@@ -47,7 +47,7 @@ impl Component for Particle {
 /// tagged with `net::Dirty`, it will be replicated to clients.
 /// Some systems spawn particles directly, such as thrusters, and no
 /// replication of the effect is needed (the ship is replicated).
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum EffectInner {
     Explosion(f32),
     MetalHit,
@@ -90,8 +90,8 @@ impl<'a> System<'a> for SysParticles {
         ): Self::SystemData,
 ){
         if !role.graphical() {
-            // If not graphical, we only send the effects to the clients once
-            effects.clear();
+            // If not graphical, the effects only get sent to the clients once,
+            // and deleted by the network system
             return;
         }
 
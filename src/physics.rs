@@ -103,6 +103,15 @@ impl Component for Position {
     type Storage = VecStorage<Self>;
 }
 
+impl Position {
+    /// Moves according to a velocity for some time.
+    pub fn advance(&mut self, vel: &Velocity, dt: f32) {
+        self.pos = vec2_add(self.pos, vec2_scale(vel.vel, dt));
+        self.rot += vel.rot * dt;
+        self.rot %= 2.0 * PI;
+    }
+}
+
 /// Velocity component, for entities that move.
 #[derive(Debug, Clone)]
 pub struct Velocity {
@@ -212,9 +221,7 @@ impl<'a> System<'a> for SysSimu {
     fn run(&mut self, (dt, mut pos, vel): Self::SystemData) {
         let dt = dt.0;
         for (pos, vel) in (&mut pos, &vel).join() {
-            pos.pos = vec2_add(pos.pos, vec2_scale(vel.vel, dt));
-            pos.rot += vel.rot * dt;
-            pos.rot %= 2.0 * PI;
+            pos.advance(vel, dt);
         }
     }
 }
@@ -376,7 +383,7 @@ fn find_collision_tree(
     }
 }
 
-fn find_collision_tree_box(
+pub fn find_collision_tree_box(
     pos1: &Position,
     box1: &AABox,
     pos2: &Position,
@@ -20,12 +20,13 @@ use crate::particles::{Effect, EffectInner, Particle, ParticleType};
 use crate::physics::{find_collision_tree_ray, DeltaTime, HitEffect, Hits,
                      LocalControl, Position, Velocity};
 use crate::utils::angle_wrap;
-use crate::{Clock, Role};
+use crate::{Clock, Role, WorldConfig};
 
 /// A ship.
 ///
 /// A ship has thrusters allowing it to rotate and move forward, and can fire
 /// projectiles.
+#[derive(Clone)]
 pub struct Ship {
     pub want_fire: bool,
     pub want_thrust: [f32; 2],
@@ -47,7 +48,67 @@ impl Ship {
         }
     }
 
+    /// Sets the thrust from the controls, using the available thrusters.
+    pub fn update_thrust(&mut self, blocky: &Blocky) {
+        let (thrust, rot) = compute_thrust(
+            blocky.blocks.iter().enumerate(),
+            |_, _| {},
+            self.want_thrust,
+            self.want_thrust_rot,
+        );
+        self.thrust = thrust;
+        self.thrust_rot = rot;
+    }
+
+    /// Updates the velocity from the thrust, and slows it down from
+    /// friction.
+    pub fn accelerate(
+        &self,
+        pos: &Position,
+        vel: &mut Velocity,
+        blocky: &Blocky,
+        dt: f32,
+    ) {
+        // Nothing left to push, this is about to be deleted (clients can
+        // get the empty structure before the deletion)
+        if blocky.blocks.is_empty() {
+            return;
+        }
+
+        let (s, c) = pos.rot.sin_cos();
+
+        // Update orientation
+        vel.rot += self.thrust_rot * dt / blocky.inertia;
+        // Update velocity
+        vel.vel = vec2_add(
+            vel.vel,
+            vec2_scale(
+                [
+                    c * self.thrust[0] - s * self.thrust[1],
+                    s * self.thrust[0] + c * self.thrust[1],
+                ],
+                dt / blocky.mass,
+            ),
+        );
+
+        // Apply friction
+        vel.vel = vec2_add(
+            vel.vel,
+            vec2_scale(vel.vel, -0.04 * dt * vec2_len(vel.vel)),
+        );
+        vel.rot -= vel.rot * vel.rot.abs() * 2.0 * dt;
+    }
+
     pub fn create(entities: &Entities, lazy: &Read<LazyUpdate>) -> Entity {
+        Ship::create_at(entities, lazy, [0.0, 0.0])
+    }
+
+    /// Creates a ship, centered on the given position.
+    pub fn create_at(
+        entities: &Entities,
+        lazy: &Read<LazyUpdate>,
+        pos: [f32; 2],
+    ) -> Entity {
         use self::BlockInner::*;
         let blocks = &[
             ([0, 0], Cockpit),
@@ -136,7 +197,7 @@ impl Ship {
         lazy.insert(
             entity,
             Position {
-                pos: center,
+                pos: vec2_add(pos, center),
                 rot: angle,
             },
         );
@@ -175,6 +236,7 @@ impl<'a> System<'a> for SysShip {
         Read<'a, LazyUpdate>,
         Read<'a, Input>,
         Read<'a, Clock>,
+        Read<'a, WorldConfig>,
         Entities<'a>,
         WriteStorage<'a, Position>,
         WriteStorage<'a, Velocity>,
@@ -193,6 +255,7 @@ impl<'a> System<'a> for SysShip {
             lazy,
             input,
             clock,
+            world,
             entities,
             mut pos,
             mut vel,
@@ -279,6 +342,8 @@ impl<'a> System<'a> for SysShip {
                                 lifetime: -1.0,
                             },
                         );
+                        #[cfg(feature = "network")]
+                        lazy.insert(new_effect, net::Dirty);
 
                         // If a cockpit died then this is no longer a ship
                         if let BlockInner::Cockpit = blk.inner {
@@ -343,9 +408,10 @@ impl<'a> System<'a> for SysShip {
             for (ent, pos, vel, _) in
                 (&*entities, &pos, &mut vel, &ship).join()
             {
-                if pos.pos[0] < -100.0 || pos.pos[0] > 100.0
-                    || pos.pos[1] < -100.0
-                    || pos.pos[1] > 100.0
+                let size = world.size;
+                if pos.pos[0] < -size || pos.pos[0] > size
+                    || pos.pos[1] < -size
+                    || pos.pos[1] > size
                 {
                     vel.vel = vec2_sub([0.0, 0.0], pos.pos);
                     vel.vel =
@@ -380,16 +446,10 @@ impl<'a> System<'a> for SysShip {
         {
             let (s, c) = pos.rot.sin_cos();
 
-            // Action thrusters from controls
-            if role.authoritative() {
-                let (thrust, rot) = compute_thrust(
-                    blocky.blocks.iter().enumerate(),
-                    |_, _| {},
-                    ship.want_thrust,
-                    ship.want_thrust_rot,
-                );
-                ship.thrust = thrust;
-                ship.thrust_rot = rot;
+            // Action thrusters from controls, also predicting our own ship
+            // on clients
+            if role.authoritative() || local.get(ent).is_some() {
+                ship.update_thrust(blocky);
             }
 
             // Update blocks
@@ -411,20 +471,8 @@ impl<'a> System<'a> for SysShip {
                 }
             }
 
-            // Apply thrust
-            // Update orientation
-            vel.rot += ship.thrust_rot * dt / blocky.inertia;
-            // Update velocity
-            vel.vel = vec2_add(
-                vel.vel,
-                vec2_scale(
-                    [
-                        c * ship.thrust[0] - s * ship.thrust[1],
-                        s * ship.thrust[0] + c * ship.thrust[1],
-                    ],
-                    dt / blocky.mass,
-                ),
-            );
+            // Apply thrust and friction
+            ship.accelerate(pos, vel, blocky, dt);
 
             // Spawn Exhaust particles
             if role.graphical() {
@@ -487,13 +535,6 @@ impl<'a> System<'a> for SysShip {
                 );
             }
 
-            // Apply friction
-            vel.vel = vec2_add(
-                vel.vel,
-                vec2_scale(vel.vel, -0.04 * dt * vec2_len(vel.vel)),
-            );
-            vel.rot -= vel.rot * vel.rot.abs() * 2.0 * dt;
-
             // Fire
             if role.authoritative() {
                 let mut fired = false;